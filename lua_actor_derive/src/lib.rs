@@ -0,0 +1,599 @@
+/*!
+Derive macros converting Rust types to & from `lua_actor::message::LuaMessage`
+(re-exported by `lua_actor::message` with the `derive` feature of `lua_actor`).
+
+* `#[derive(IntoLuaMessage)]` implements `From<T> for LuaMessage` (& `MultiLuaMessage`).
+* `#[derive(FromLuaMessage)]` implements `lua_actor::message::FromLuaMessage`,
+  returning `None` when the message doesn't fit (as `Option<T>::from(LuaMessage)`).
+
+Structs with named fields are tables, tuple structs are arrays,
+and enums are tables tagged by the variant name (`{type = "Variant", ...}`):
+named fields are in the table, a single unnamed field is `value`
+and many unnamed fields are the `values` array.
+
+Attributes:
+
+* `#[lua(tag = "kind")]` on enums: the key of the variant name (`type` by default).
+* `#[lua(rename = "name")]` on fields & variants.
+* `#[lua(skip)]` on fields: neither converted nor read (`Default::default()` when read).
+* `#[lua(default)]` or `#[lua(default = "path::to::fn")]` on fields: the value when missing (`nil`).
+*/
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, ExprPath, Fields, GenericArgument,
+    Ident, LitStr, PathArguments, Type,
+};
+
+#[proc_macro_derive(IntoLuaMessage, attributes(lua))]
+pub fn derive_into_lua_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    into_lua_message(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(FromLuaMessage, attributes(lua))]
+pub fn derive_from_lua_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_lua_message(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct LuaAttrs {
+    rename: Option<String>,
+    skip: bool,
+    default: Option<Option<ExprPath>>,
+    tag: Option<String>,
+}
+
+impl LuaAttrs {
+    fn parse(attrs: &[Attribute]) -> Result<LuaAttrs, Error> {
+        let mut lua = LuaAttrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("lua")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    lua.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("tag") {
+                    lua.tag = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("skip") {
+                    lua.skip = true;
+                } else if meta.path.is_ident("default") {
+                    lua.default = Some(
+                        match meta.input.is_empty() || meta.input.peek(syn::Token![,]) {
+                            true => None,
+                            false => Some(meta.value()?.parse::<LitStr>()?.parse::<ExprPath>()?),
+                        },
+                    );
+                } else {
+                    return Err(meta.error("unknown lua attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(lua)
+    }
+
+    // The value of a field which is skipped or missing.
+    fn default_value(&self) -> TokenStream2 {
+        match self.default {
+            Some(Some(ref path)) => quote!(#path()),
+            _ => quote!(::std::default::Default::default()),
+        }
+    }
+}
+
+// A field to convert: its binding in patterns & its key in tables.
+struct Field {
+    binding: Ident,
+    member: TokenStream2,
+    key: String,
+    ty: Type,
+    attrs: LuaAttrs,
+}
+
+fn fields(fields: &Fields) -> Result<Vec<Field>, Error> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let attrs = LuaAttrs::parse(&field.attrs)?;
+            let (binding, member, key) = match field.ident {
+                Some(ref ident) => (
+                    ident.clone(),
+                    quote!(#ident),
+                    attrs.rename.clone().unwrap_or_else(|| ident.to_string()),
+                ),
+                None => {
+                    let index = syn::Index::from(i);
+                    (
+                        Ident::new(&format!("field{}", i), Span::call_site()),
+                        quote!(#index),
+                        i.to_string(),
+                    )
+                }
+            };
+            Ok(Field {
+                binding,
+                member,
+                key,
+                ty: field.ty.clone(),
+                attrs,
+            })
+        })
+        .collect()
+}
+
+fn generic_argument<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != name {
+        return None;
+    }
+    match segment.arguments {
+        PathArguments::AngleBracketed(ref args) if args.args.len() == 1 => match args.args[0] {
+            GenericArgument::Type(ref ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether the field is `Vec<u8>`, converted as a whole to `LuaMessage::Bytes`.
+fn is_bytes(ty: &Type) -> bool {
+    match generic_argument(ty, "Vec") {
+        Some(Type::Path(item)) => item.qself.is_none() && item.path.is_ident("u8"),
+        _ => false,
+    }
+}
+
+// `LuaMessage` of the value; `Option` & `Vec` fields are converted by their items,
+// so the derived types could be in them.
+fn into_message(ty: &Type, value: TokenStream2) -> TokenStream2 {
+    if is_bytes(ty) {
+        quote!(::lua_actor::message::LuaMessage::Bytes(#value))
+    } else if let Some(item) = generic_argument(ty, "Option") {
+        let item = into_message(item, quote!(value));
+        quote! {
+            match #value {
+                ::std::option::Option::Some(value) => #item,
+                ::std::option::Option::None => ::lua_actor::message::LuaMessage::Nil,
+            }
+        }
+    } else if let Some(item) = generic_argument(ty, "Vec") {
+        let item = into_message(item, quote!(value));
+        quote! {
+            ::lua_actor::message::LuaMessage::Array(
+                #value.into_iter().map(|value| #item).collect()
+            )
+        }
+    } else {
+        quote!(::std::convert::Into::<::lua_actor::message::LuaMessage>::into(#value))
+    }
+}
+
+// The value of the field read from the message (returning `None` when it doesn't fit).
+fn from_message(field: &Field, message: TokenStream2) -> TokenStream2 {
+    let ty = &field.ty;
+    let convert = if is_bytes(ty) {
+        // Strings which are UTF-8 are bytes too.
+        quote!(::std::option::Option::<#ty>::from(message)?)
+    } else {
+        quote!(<#ty as ::lua_actor::message::FromLuaMessage>::from_lua_message(message)?)
+    };
+    match field.attrs.default {
+        Some(_) => {
+            let default = field.attrs.default_value();
+            quote! {
+                match #message {
+                    ::lua_actor::message::LuaMessage::Nil => #default,
+                    message => #convert,
+                }
+            }
+        }
+        None => quote!({
+            let message = #message;
+            #convert
+        }),
+    }
+}
+
+fn table_of(fields: &[Field]) -> TokenStream2 {
+    let inserts = fields.iter().filter(|f| !f.attrs.skip).map(|field| {
+        let key = &field.key;
+        let value = into_message(&field.ty, {
+            let binding = &field.binding;
+            quote!(#binding)
+        });
+        quote!(table.insert(::std::string::String::from(#key), #value);)
+    });
+    quote! {
+        let mut table = ::std::collections::HashMap::new();
+        #(#inserts)*
+    }
+}
+
+fn array_of(fields: &[Field]) -> TokenStream2 {
+    let items = fields.iter().filter(|f| !f.attrs.skip).map(|field| {
+        let binding = &field.binding;
+        into_message(&field.ty, quote!(#binding))
+    });
+    quote!(::lua_actor::message::LuaMessage::Array(
+        ::std::collections::VecDeque::from(vec![#(#items),*])
+    ))
+}
+
+// Read the fields (bound by their bindings) from `table`.
+fn read_table(fields: &[Field]) -> TokenStream2 {
+    let reads = fields.iter().map(|field| {
+        let binding = &field.binding;
+        let value = match field.attrs.skip {
+            true => field.attrs.default_value(),
+            false => {
+                let key = &field.key;
+                from_message(
+                    field,
+                    quote!(table
+                        .remove(#key)
+                        .unwrap_or(::lua_actor::message::LuaMessage::Nil)),
+                )
+            }
+        };
+        quote!(let #binding = #value;)
+    });
+    quote!(#(#reads)*)
+}
+
+// Read the fields (bound by their bindings) from the `items` iterator.
+fn read_array(fields: &[Field]) -> TokenStream2 {
+    let reads = fields.iter().map(|field| {
+        let binding = &field.binding;
+        let value = match field.attrs.skip {
+            true => field.attrs.default_value(),
+            false => from_message(
+                field,
+                quote!(items
+                    .next()
+                    .unwrap_or(::lua_actor::message::LuaMessage::Nil)),
+            ),
+        };
+        quote!(let #binding = #value;)
+    });
+    quote!(#(#reads)*)
+}
+
+fn construct(path: TokenStream2, fields: &Fields, bound: &[Field]) -> TokenStream2 {
+    let members = bound.iter().map(|f| &f.member);
+    let bindings = bound.iter().map(|f| &f.binding);
+    match fields {
+        Fields::Named(_) => quote!(#path { #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(#path { #(#members: #bindings),* }),
+        Fields::Unit => path,
+    }
+}
+
+fn variant_key(variant: &syn::Variant) -> Result<String, Error> {
+    Ok(LuaAttrs::parse(&variant.attrs)?
+        .rename
+        .unwrap_or_else(|| variant.ident.to_string()))
+}
+
+fn into_lua_message(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let bound = fields(&data.fields)?;
+            let pattern = construct(quote!(#name), &data.fields, &bound);
+            match data.fields {
+                Fields::Named(_) => {
+                    let table = table_of(&bound);
+                    quote! {
+                        #[allow(unused_variables)]
+                        let #pattern = value;
+                        #table
+                        ::lua_actor::message::LuaMessage::Table(table)
+                    }
+                }
+                Fields::Unnamed(_) => {
+                    let array = array_of(&bound);
+                    quote! {
+                        #[allow(unused_variables)]
+                        let #pattern = value;
+                        #array
+                    }
+                }
+                Fields::Unit => {
+                    return Err(Error::new_spanned(
+                        name,
+                        "IntoLuaMessage can't be derived for unit structs",
+                    ))
+                }
+            }
+        }
+        Data::Enum(ref data) => {
+            let tag = LuaAttrs::parse(&input.attrs)?
+                .tag
+                .unwrap_or_else(|| "type".to_string());
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let ident = &variant.ident;
+                    let key = variant_key(variant)?;
+                    let bound = fields(&variant.fields)?;
+                    let pattern = construct(quote!(#name::#ident), &variant.fields, &bound);
+                    let fields = match variant.fields {
+                        Fields::Named(_) => {
+                            let table = table_of(&bound);
+                            quote! {
+                                #table
+                                table
+                            }
+                        }
+                        Fields::Unnamed(_) => {
+                            let unskipped =
+                                bound.iter().filter(|f| !f.attrs.skip).collect::<Vec<_>>();
+                            let (key, value) = match unskipped.len() {
+                                1 => (
+                                    "value",
+                                    into_message(&unskipped[0].ty, {
+                                        let binding = &unskipped[0].binding;
+                                        quote!(#binding)
+                                    }),
+                                ),
+                                _ => ("values", array_of(&bound)),
+                            };
+                            quote! {
+                                let mut table = ::std::collections::HashMap::new();
+                                table.insert(::std::string::String::from(#key), #value);
+                                table
+                            }
+                        }
+                        Fields::Unit => quote!(::std::collections::HashMap::new()),
+                    };
+                    Ok(quote! {
+                        #[allow(unused_variables)]
+                        #pattern => {
+                            let mut table: ::std::collections::HashMap<
+                                ::std::string::String,
+                                ::lua_actor::message::LuaMessage,
+                            > = { #fields };
+                            table.insert(
+                                ::std::string::String::from(#tag),
+                                ::lua_actor::message::LuaMessage::from(#key),
+                            );
+                            ::lua_actor::message::LuaMessage::Table(table)
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            quote! {
+                match value {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                name,
+                "IntoLuaMessage can't be derived for unions",
+            ))
+        }
+    };
+
+    let mut generics = input.generics.clone();
+    for param in input.generics.type_params() {
+        let ident = &param.ident;
+        generics.make_where_clause().predicates.push(
+            syn::parse_quote!(#ident: ::std::convert::Into<::lua_actor::message::LuaMessage>),
+        );
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::std::convert::From<#name #ty_generics>
+            for ::lua_actor::message::LuaMessage #where_clause
+        {
+            fn from(value: #name #ty_generics) -> Self {
+                #body
+            }
+        }
+        impl #impl_generics ::std::convert::From<#name #ty_generics>
+            for ::lua_actor::message::MultiLuaMessage #where_clause
+        {
+            fn from(value: #name #ty_generics) -> Self {
+                ::std::convert::Into::<::lua_actor::message::LuaMessage>::into(value).into()
+            }
+        }
+    })
+}
+
+fn from_lua_message(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let bound = fields(&data.fields)?;
+            let value = construct(quote!(#name), &data.fields, &bound);
+            match data.fields {
+                Fields::Named(_) => {
+                    let reads = read_table(&bound);
+                    quote! {
+                        match message {
+                            ::lua_actor::message::LuaMessage::Table(mut table) => {
+                                #reads
+                                ::std::option::Option::Some(#value)
+                            }
+                            // Tables with other keys too (ignored, as the unknown fields).
+                            ::lua_actor::message::LuaMessage::Map(map) => {
+                                Self::from_lua_message(::lua_actor::message::LuaMessage::Table(
+                                    map.into_iter()
+                                        .filter_map(|(key, value)| match key.into_message() {
+                                            ::lua_actor::message::LuaMessage::String(key) => {
+                                                ::std::option::Option::Some((key, value))
+                                            }
+                                            _ => ::std::option::Option::None,
+                                        })
+                                        .collect(),
+                                ))
+                            }
+                            // Empty Lua tables.
+                            ::lua_actor::message::LuaMessage::Array(ref items) if items.is_empty() => {
+                                Self::from_lua_message(::lua_actor::message::LuaMessage::Table(
+                                    ::std::collections::HashMap::new(),
+                                ))
+                            }
+                            _ => ::std::option::Option::None,
+                        }
+                    }
+                }
+                Fields::Unnamed(_) => {
+                    let reads = read_array(&bound);
+                    quote! {
+                        match message {
+                            ::lua_actor::message::LuaMessage::Array(items) => {
+                                #[allow(unused_mut, unused_variables)]
+                                let mut items = items.into_iter();
+                                #reads
+                                ::std::option::Option::Some(#value)
+                            }
+                            ::lua_actor::message::LuaMessage::Table(ref table) if table.is_empty() => {
+                                Self::from_lua_message(::lua_actor::message::LuaMessage::Array(
+                                    ::std::collections::VecDeque::new(),
+                                ))
+                            }
+                            // Arrays with many holes.
+                            items @ ::lua_actor::message::LuaMessage::SparseArray(_) => {
+                                let items = <::std::vec::Vec<::lua_actor::message::LuaMessage>
+                                    as ::lua_actor::message::FromLuaMessage>::from_lua_message(items)?;
+                                Self::from_lua_message(::lua_actor::message::LuaMessage::Array(
+                                    items.into(),
+                                ))
+                            }
+                            // Arrays with other keys too (e.g. `table.pack`'s `n`).
+                            ::lua_actor::message::LuaMessage::Map(map) => {
+                                Self::from_lua_message(::lua_actor::message::LuaMessage::SparseArray(
+                                    map.into_iter()
+                                        .filter_map(|(key, value)| match key.into_message() {
+                                            ::lua_actor::message::LuaMessage::Integer(index) => {
+                                                ::std::option::Option::Some((index, value))
+                                            }
+                                            _ => ::std::option::Option::None,
+                                        })
+                                        .collect(),
+                                ))
+                            }
+                            _ => ::std::option::Option::None,
+                        }
+                    }
+                }
+                Fields::Unit => {
+                    return Err(Error::new_spanned(
+                        name,
+                        "FromLuaMessage can't be derived for unit structs",
+                    ))
+                }
+            }
+        }
+        Data::Enum(ref data) => {
+            let tag = LuaAttrs::parse(&input.attrs)?
+                .tag
+                .unwrap_or_else(|| "type".to_string());
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let ident = &variant.ident;
+                    let key = variant_key(variant)?;
+                    let bound = fields(&variant.fields)?;
+                    let value = construct(quote!(#name::#ident), &variant.fields, &bound);
+                    let reads = match variant.fields {
+                        Fields::Named(_) => read_table(&bound),
+                        Fields::Unnamed(_) => {
+                            let unskipped = bound.iter().filter(|f| !f.attrs.skip).count();
+                            let reads = read_array(&bound);
+                            match unskipped {
+                                1 => quote! {
+                                    let mut items = table.remove("value").into_iter();
+                                    #reads
+                                },
+                                _ => quote! {
+                                    let mut items = match table.remove("values") {
+                                        ::std::option::Option::Some(
+                                            ::lua_actor::message::LuaMessage::Array(items),
+                                        ) => items.into_iter(),
+                                        ::std::option::Option::Some(
+                                            items @ ::lua_actor::message::LuaMessage::SparseArray(_),
+                                        ) => <::std::vec::Vec<::lua_actor::message::LuaMessage>
+                                            as ::lua_actor::message::FromLuaMessage>::from_lua_message(items)?
+                                            .into_iter()
+                                            .collect::<::std::collections::VecDeque<_>>()
+                                            .into_iter(),
+                                        _ => return ::std::option::Option::None,
+                                    };
+                                    #reads
+                                },
+                            }
+                        }
+                        Fields::Unit => quote!(),
+                    };
+                    Ok(quote! {
+                        #key => {
+                            #reads
+                            ::std::option::Option::Some(#value)
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            quote! {
+                let mut table = match message {
+                    ::lua_actor::message::LuaMessage::Table(table) => table,
+                    _ => return ::std::option::Option::None,
+                };
+                let variant = match table.remove(#tag) {
+                    ::std::option::Option::Some(::lua_actor::message::LuaMessage::String(variant)) => variant,
+                    _ => return ::std::option::Option::None,
+                };
+                match variant.as_str() {
+                    #(#arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                name,
+                "FromLuaMessage can't be derived for unions",
+            ))
+        }
+    };
+
+    let mut generics = input.generics.clone();
+    for param in input.generics.type_params() {
+        let ident = &param.ident;
+        generics
+            .make_where_clause()
+            .predicates
+            .push(syn::parse_quote!(#ident: ::lua_actor::message::FromLuaMessage));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::lua_actor::message::FromLuaMessage for #name #ty_generics #where_clause {
+            #[allow(unused_mut, unused_variables)]
+            fn from_lua_message(
+                message: ::lua_actor::message::LuaMessage,
+            ) -> ::std::option::Option<Self> {
+                #body
+            }
+        }
+    })
+}
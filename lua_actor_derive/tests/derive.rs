@@ -0,0 +1,215 @@
+extern crate lua_actor;
+#[macro_use]
+extern crate lua_actor_derive;
+
+use std::collections::{BTreeMap, HashMap};
+
+use lua_actor::actor::Actor;
+use lua_actor::message::{FromLuaMessage, LuaMessage};
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Item {
+    name: String,
+    #[lua(rename = "unit_price")]
+    price: f64,
+    tags: Vec<String>,
+    note: Option<String>,
+    #[lua(skip)]
+    cached: Option<i64>,
+    #[lua(default = "default_quantity")]
+    quantity: i64,
+}
+
+fn default_quantity() -> i64 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Blob {
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Point(i64, i64);
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Slots(Option<i64>, Option<i64>, i64);
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+#[lua(tag = "kind")]
+enum Shape {
+    Empty,
+    #[lua(rename = "circle")]
+    Circle {
+        center: Point,
+        radius: f64,
+    },
+    Line(Point, Point),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Drawing<T: Default> {
+    shapes: Vec<Shape>,
+    #[lua(default)]
+    meta: T,
+}
+
+fn item() -> Item {
+    Item {
+        name: "pen".to_string(),
+        price: 1.5,
+        tags: vec!["office".to_string()],
+        note: None,
+        cached: Some(3),
+        quantity: 2,
+    }
+}
+
+#[test]
+fn structs() {
+    let message = LuaMessage::from(item());
+    match message {
+        LuaMessage::Table(ref table) => {
+            assert_eq!(Some(&LuaMessage::from(1.5)), table.get("unit_price"));
+            assert_eq!(None, table.get("cached"));
+            assert_eq!(Some(&LuaMessage::Nil), table.get("note"));
+        }
+        _ => panic!("not a table: {:?}", message),
+    }
+    let back = Item::from_lua_message(message).unwrap();
+    assert_eq!(
+        Item {
+            cached: None,
+            ..item()
+        },
+        back
+    );
+
+    let mut table = HashMap::new();
+    table.insert("name".to_string(), LuaMessage::from("cup"));
+    table.insert("unit_price".to_string(), LuaMessage::from(2));
+    table.insert("tags".to_string(), LuaMessage::Table(HashMap::new()));
+    let cup = Item::from_lua_message(LuaMessage::from(table.clone())).unwrap();
+    assert_eq!((2.0, 1, None), (cup.price, cup.quantity, cup.note));
+
+    table.remove("name");
+    assert_eq!(None, Item::from_lua_message(LuaMessage::from(table)));
+    assert_eq!(None, Item::from_lua_message(LuaMessage::from(1)));
+
+    assert_eq!(
+        LuaMessage::from_slice(vec![1, 2]),
+        LuaMessage::from(Point(1, 2))
+    );
+    assert_eq!(
+        Some(Point(1, 2)),
+        Point::from_lua_message(LuaMessage::from_slice(vec![1, 2]))
+    );
+    // Arrays with many holes come as `SparseArray`.
+    let mut sparse = BTreeMap::new();
+    sparse.insert(3, LuaMessage::from(7));
+    assert_eq!(
+        Some(Slots(None, None, 7)),
+        Slots::from_lua_message(LuaMessage::SparseArray(sparse))
+    );
+    // Tables with other keys too come as `Map`.
+    let act = Actor::new_with_handler(None);
+    let packed = act.eval("table.pack(1, 2)").unwrap();
+    assert!(matches!(packed, LuaMessage::Map(_)));
+    assert_eq!(Some(Point(1, 2)), Point::from_lua_message(packed));
+    let tagged = act
+        .eval("{name = 'pad', unit_price = 3, tags = {}, [true] = 'extra'}")
+        .unwrap();
+    assert!(matches!(tagged, LuaMessage::Map(_)));
+    assert_eq!(
+        Some("pad".to_string()),
+        Item::from_lua_message(tagged).map(|item| item.name)
+    );
+
+    let blob = Blob { data: vec![0, 255] };
+    assert_eq!(
+        "{data = \"\\x00\\xff\"}",
+        LuaMessage::from(blob.clone()).to_string()
+    );
+    assert_eq!(
+        Some(blob.clone()),
+        Blob::from_lua_message(LuaMessage::from(blob))
+    );
+    let mut table = HashMap::new();
+    table.insert("data".to_string(), LuaMessage::from("hi"));
+    assert_eq!(
+        Some(Blob {
+            data: b"hi".to_vec()
+        }),
+        Blob::from_lua_message(LuaMessage::from(table))
+    );
+}
+
+#[test]
+fn enums() {
+    let shapes = vec![
+        Shape::Empty,
+        Shape::Circle {
+            center: Point(0, 0),
+            radius: 2.0,
+        },
+        Shape::Line(Point(0, 0), Point(1, 1)),
+        Shape::Label("x".to_string()),
+    ];
+    let drawing = Drawing {
+        shapes: shapes.clone(),
+        meta: 7,
+    };
+    let message = LuaMessage::from(drawing.clone());
+    assert_eq!(
+        r#"{meta = 7, shapes = {{kind = "Empty"}, {center = {0, 0}, kind = "circle", radius = 2.0}, {kind = "Line", values = {{0, 0}, {1, 1}}}, {kind = "Label", value = "x"}}}"#,
+        message.to_string()
+    );
+    assert_eq!(Some(drawing), Drawing::from_lua_message(message));
+
+    let mut unknown = HashMap::new();
+    unknown.insert("kind".to_string(), LuaMessage::from("Square"));
+    assert_eq!(None, Shape::from_lua_message(LuaMessage::from(unknown)));
+}
+
+#[derive(Debug, Clone, PartialEq, IntoLuaMessage, FromLuaMessage)]
+struct Counter {
+    hits: u64,
+}
+
+#[test]
+fn unsigned() {
+    let counter = Counter { hits: 1 << 40 };
+    assert_eq!(
+        Some(counter.clone()),
+        Counter::from_lua_message(LuaMessage::from(counter))
+    );
+}
+
+#[test]
+fn with_actor() {
+    let act = Actor::new_with_handler(None);
+    act.exec(
+        r#"
+        function scale(shape, factor)
+            shape.radius = shape.radius * factor
+            return shape
+        end
+    "#,
+    )
+    .unwrap();
+    let circle = Shape::Circle {
+        center: Point(1, 2),
+        radius: 1.5,
+    };
+    let scaled = act
+        .call_multi("scale", vec![LuaMessage::from(circle), LuaMessage::from(2)])
+        .unwrap();
+    assert_eq!(
+        Some(Shape::Circle {
+            center: Point(1, 2),
+            radius: 3.0,
+        }),
+        Shape::from_lua_message(Vec::<LuaMessage>::from(scaled).remove(0))
+    );
+}
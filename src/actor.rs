@@ -1,24 +1,94 @@
-use std::sync::{Arc, Mutex};
+#[cfg(feature = "serde")]
+use std::any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "debugger")]
+use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, Weak,
+};
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+#[cfg(test)]
+use std::thread;
+use std::time::Duration;
 
+use coverage::{self, Coverage};
+#[cfg(feature = "debugger")]
+use debugger::{self, Debugger};
+use error::ScriptError;
+use event::{EventBus, EventSubscription};
 use fp_rust::{
     common::{RawFunc, SubscriptionFunc},
     handler::{Handler, HandlerThread},
     sync::{CountDownLatch, Will, WillAsync},
 };
-use message::{LuaMessage, MultiLuaMessage};
-use rlua::{Chunk, Context, Error, FromLua, FromLuaMulti, Function, Lua, Table, ToLua, ToLuaMulti};
+use hook::{self, HookMask, Hooks};
+use message::{
+    self, lua_type_name, ConversionOptions, LuaFunctionRef, LuaMessage, MultiLuaMessage,
+};
+#[cfg(feature = "serde")]
+use message::{from_message, to_multi_message};
+use profiler::{self, Profile, Profiler};
+use rlua::{
+    AnyUserData, Chunk, Context, Error, FromLua, FromLuaMulti, Function, Lua, Nil, Table, Thread,
+    ThreadStatus, ToLua, ToLuaMulti, UserData, Value, Variadic,
+};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+const PENDING_COROUTINES_KEY: &str = "lua_actor.pending_coroutines";
+const SPAWNED_COROUTINES_KEY: &str = "lua_actor.spawned_coroutines";
+const CHUNKS_KEY: &str = "lua_actor.chunks";
+// `load` & `string.dump` as they were before any script could replace them.
+const BUILTINS_KEY: &str = "lua_actor.builtins";
+const ASYNC_FN_WRAPPER: &str = r#"
+local start = ...
+return function(...)
+    if not coroutine.isyieldable() then
+        error("async function called outside of a coroutine (see Actor::spawn)", 2)
+    end
+    local ready, ok, value = start(...)
+    if not ready then
+        ok, value = coroutine.yield()
+    end
+    if ok then
+        return value
+    end
+    error(value, 2)
+end
+"#;
+
+static NEXT_PENDING_ID: AtomicUsize = AtomicUsize::new(1);
+static NEXT_CHUNK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The id of a chunk compiled by an `Actor` (see `Actor::compile`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkId(usize);
+
+// Compiled chunks by name, with the hash of their source.
+type ChunkCache = Arc<Mutex<HashMap<String, (u64, ChunkId)>>>;
 
 #[derive(Clone)]
 pub struct Actor {
     handler: Option<Arc<Mutex<HandlerThread>>>,
     lua: Arc<Mutex<Lua>>,
+    events: EventBus,
+    chunks: ChunkCache,
+    hooks: Hooks,
 }
 
 impl Default for Actor {
     fn default() -> Self {
         Actor {
             handler: Some(HandlerThread::new_with_mutex()),
-            lua: Arc::new(Mutex::new(Lua::new())),
+            lua: Arc::new(Mutex::new(Self::keep_builtins(Lua::new()))),
+            events: EventBus::default(),
+            chunks: Default::default(),
+            hooks: Default::default(),
         }
     }
 }
@@ -46,9 +116,15 @@ impl Actor {
     pub fn lua(&self) -> Arc<Mutex<Lua>> {
         self.lua.clone()
     }
+    /// Use another VM; `dump` & `load_bytecode` keep its `string.dump` & `load` as they are now.
     #[inline]
     pub fn set_lua(&mut self, lua: Arc<Mutex<Lua>>) {
+        lua.lock()
+            .unwrap()
+            .context(|lua| Self::keep_builtins_in(lua).ok());
         self.lua = lua;
+        self.chunks = Default::default();
+        self.hooks = Default::default();
     }
     #[inline]
     pub fn stop_handler(&self) {
@@ -63,11 +139,11 @@ impl Actor {
         }
     }
     #[inline]
-    fn wait_async_lua_message_result(
+    fn wait_async_lua_message_result<R: Clone + Send + Sync + 'static>(
         &self,
         _handler: &Arc<Mutex<HandlerThread>>,
-        func: impl FnOnce() -> Result<LuaMessage, Error> + Send + Sync + 'static + Clone,
-    ) -> Result<LuaMessage, Error> {
+        func: impl FnOnce() -> R + Send + Sync + 'static + Clone,
+    ) -> R {
         let func = Arc::new(Mutex::new(func));
 
         let done_latch = CountDownLatch::new(1);
@@ -83,8 +159,20 @@ impl Actor {
 
         will.result().unwrap()
     }
+    // Run the function with the VM, on the handler if any.
+    fn with_lua<R: Clone + Send + Sync + 'static>(
+        &self,
+        func: impl FnOnce(Context) -> R + Send + Sync + 'static + Clone,
+    ) -> R {
+        let lua = self.lua.clone();
+        let func = move || lua.lock().unwrap().context(func);
+        match self.handler.clone() {
+            Some(_handler) => self.wait_async_lua_message_result(&_handler, func),
+            None => func(),
+        }
+    }
 
-    pub fn set_global(&self, key: &'static str, value: LuaMessage) -> Result<(), Error> {
+    pub fn set_global(&self, key: &str, value: LuaMessage) -> Result<(), Error> {
         self.lua
             .clone()
             .lock()
@@ -92,16 +180,113 @@ impl Actor {
             .context(|lua| match self.handler.clone() {
                 Some(_handler) => {
                     let lua_vm = self.lua.clone();
+                    let key = key.to_string();
                     _handler.lock().unwrap().post(RawFunc::new(move || {
                         lua_vm.lock().unwrap().context(|lua| {
-                            let _ = Self::set_global_raw(lua, key, value.clone());
+                            let _ = Self::set_path(lua, &key, value.clone());
                         })
                     }));
                     Ok(())
                 }
-                None => Self::set_global_raw(lua, key, value),
+                None => Self::set_path(lua, key, value),
             })
     }
+    /// Resolve a dotted path from the globals (e.g. `mod.sub.fn`), or a method as `obj:method`.
+    ///
+    /// It returns the value, along with the object when it's a method.
+    /// Missing intermediate segments are reported by name.
+    pub fn resolve_path<'lua>(
+        lua: Context<'lua>,
+        path: &str,
+    ) -> Result<(Option<Value<'lua>>, Value<'lua>), Error> {
+        let (segments, method) = Self::split_path(path)?;
+
+        let mut object = None;
+        let mut value = Value::Table(lua.globals());
+        for (i, segment) in segments.iter().enumerate() {
+            let next = match value {
+                Value::Table(ref t) => t.get::<_, Value>(*segment)?,
+                Value::UserData(_) => lua
+                    .load("local o, k = ... return o[k]")
+                    .call::<_, Value>((value.clone(), *segment))?,
+                _ => {
+                    return Err(Error::RuntimeError(format!(
+                        "cannot resolve '{}': '{}' is {}",
+                        path,
+                        segments[..i].join("."),
+                        lua_type_name(&value),
+                    )))
+                }
+            };
+            object = Some(value);
+            value = next;
+        }
+        Ok((method.and(object), value))
+    }
+    // The segments of a path, the last one being the method of `obj:method`.
+    fn split_path(path: &str) -> Result<(Vec<&str>, Option<&str>), Error> {
+        let (object_path, method) = match path.find(':') {
+            Some(i) => (&path[..i], Some(&path[i + 1..])),
+            None => (path, None),
+        };
+        let mut segments = object_path.split('.').collect::<Vec<_>>();
+        segments.extend(method);
+        // The method is last (`a:b.c` & `a:b:c` are not paths).
+        let misplaced = method.is_some_and(|m| m.contains(['.', ':']));
+        if misplaced || segments.iter().any(|s| s.is_empty()) {
+            return Err(Error::RuntimeError(format!("invalid path '{}'", path)));
+        }
+        Ok((segments, method))
+    }
+    pub fn get_path<'lua, V: FromLua<'lua>>(lua: Context<'lua>, path: &str) -> Result<V, Error> {
+        if Self::split_path(path)?.1.is_some() {
+            return Err(Error::RuntimeError(format!(
+                "method path '{}' is only valid for calls",
+                path
+            )));
+        }
+        let (_, value) = Self::resolve_path(lua, path)?;
+        V::from_lua(value, lua)
+    }
+    pub fn set_path<'lua, V: ToLua<'lua>>(
+        lua: Context<'lua>,
+        path: &str,
+        value: V,
+    ) -> Result<(), Error> {
+        Self::split_path(path)?;
+        let (parent, key) = match path.rfind('.') {
+            Some(i) => (Self::get_path::<Value>(lua, &path[..i])?, &path[i + 1..]),
+            None => (Value::Table(lua.globals()), path),
+        };
+        if key.is_empty() || key.contains(':') {
+            return Err(Error::RuntimeError(format!("invalid path '{}'", path)));
+        }
+        match parent {
+            Value::Table(t) => t.set(key, value),
+            _ => Err(Error::RuntimeError(format!(
+                "cannot set '{}': '{}' is {}",
+                path,
+                &path[..path.len() - key.len() - 1],
+                lua_type_name(&parent),
+            ))),
+        }
+    }
+    /// Call the function of a path (see `resolve_path`), passing the object to methods.
+    pub fn call_path<'lua, A: ToLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
+        lua: Context<'lua>,
+        path: &str,
+        args: A,
+    ) -> Result<R, Error> {
+        match Self::resolve_path(lua, path)? {
+            (Some(object), Value::Function(func)) => func.bind(object)?.call(args),
+            (None, Value::Function(func)) => func.call(args),
+            (_, value) => Err(Error::RuntimeError(format!(
+                "cannot call '{}': it is {}",
+                path,
+                lua_type_name(&value),
+            ))),
+        }
+    }
     #[inline]
     pub fn set_global_raw<'lua, K: ToLua<'lua>, V: ToLua<'lua>>(
         lua: Context<'lua>,
@@ -111,11 +296,12 @@ impl Actor {
         lua.globals().set::<_, V>(key, value)
     }
 
-    pub fn get_global(&self, key: &'static str) -> Result<LuaMessage, Error> {
+    pub fn get_global(&self, key: &str) -> Result<LuaMessage, Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
-                self.wait_async_lua_message_result(&_handler, move || Self::_get_global(&lua, key))
+                let key = key.to_string();
+                self.wait_async_lua_message_result(&_handler, move || Self::_get_global(&lua, &key))
             }
             None => Self::_get_global(&self.lua.clone(), key),
         }
@@ -141,7 +327,7 @@ impl Actor {
     #[inline]
     fn _get_global(lua: &Arc<Mutex<Lua>>, key: &str) -> Result<LuaMessage, Error> {
         let vm = lua.lock().unwrap();
-        vm.context(|lua| Self::get_global_raw::<_, LuaMessage>(lua, key))
+        vm.context(|lua| Self::get_path::<LuaMessage>(lua, key))
     }
 
     #[inline]
@@ -202,111 +388,439 @@ impl Actor {
         */
     }
     #[inline]
-    pub fn load<'lua>(lua: Context<'lua>, source: &'lua str) -> Result<Chunk<'lua, 'lua>, Error> {
-        Ok(lua.load(source))
+    pub fn load<'lua, 'a>(lua: Context<'lua>, source: &'a str) -> Result<Chunk<'lua, 'a>, Error> {
+        Self::load_chunk(lua, source, None)
+    }
+    /// Load the source as a chunk named `chunk_name` (e.g. its file path).
+    ///
+    /// Errors of the actor methods are then located in that chunk (see `ScriptError`).
+    #[inline]
+    pub fn load_named<'lua, 'a>(
+        lua: Context<'lua>,
+        source: &'a str,
+        chunk_name: &str,
+    ) -> Result<Chunk<'lua, 'a>, Error> {
+        Self::load_chunk(lua, source, Some(chunk_name))
+    }
+    fn load_chunk<'lua, 'a>(
+        lua: Context<'lua>,
+        source: &'a str,
+        chunk_name: Option<&str>,
+    ) -> Result<Chunk<'lua, 'a>, Error> {
+        match chunk_name {
+            Some(name) => {
+                ScriptError::remember_source(lua, name, source)?;
+                lua.load(source).set_name(&format!("@{}", name))
+            }
+            None => Ok(lua.load(source)),
+        }
     }
-    pub fn load_nowait(&self, source: &'static str) -> Result<(), Error> {
+    pub fn load_nowait(&self, source: &str) -> Result<(), Error> {
+        self._load_nowait(source, None)
+    }
+    pub fn load_named_nowait(&self, source: &str, chunk_name: &str) -> Result<(), Error> {
+        self._load_nowait(source, Some(chunk_name))
+    }
+    fn _load_nowait(&self, source: &str, chunk_name: Option<&str>) -> Result<(), Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
+                let (source, chunk_name) = (source.to_string(), chunk_name.map(str::to_string));
                 _handler.lock().unwrap().post(RawFunc::new(move || {
                     let lua = lua.lock().unwrap();
                     lua.context(|lua| {
-                        let _ = Self::load(lua, source).unwrap().exec();
+                        let _ = Self::load_chunk(lua, &source, chunk_name.as_deref())
+                            .unwrap()
+                            .exec();
                     })
                 }));
             }
             None => {
                 let lua = self.lua.lock().unwrap();
                 lua.context(|lua| {
-                    let _ = Self::load(lua, source).unwrap().exec();
+                    let _ = Self::load_chunk(lua, source, chunk_name).unwrap().exec();
                 })
             }
         }
 
         Ok(())
     }
-    pub fn exec(&self, source: &'static str) -> Result<LuaMessage, Error> {
+    pub fn exec(&self, source: &str) -> Result<LuaMessage, Error> {
+        self._exec_named(source, None)
+    }
+    /// Run the source as a chunk named `chunk_name` (see `load_named`).
+    pub fn exec_named(&self, source: &str, chunk_name: &str) -> Result<LuaMessage, Error> {
+        self._exec_named(source, Some(chunk_name))
+    }
+    fn _exec_named(&self, source: &str, chunk_name: Option<&str>) -> Result<LuaMessage, Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
-                self.wait_async_lua_message_result(&_handler, move || Self::_exec(&lua, source))
+                let (source, chunk_name) = (source.to_string(), chunk_name.map(str::to_string));
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_exec(&lua, &source, chunk_name.as_deref())
+                })
             }
-            None => Self::_exec(&self.lua.clone(), source),
+            None => Self::_exec(&self.lua.clone(), source, chunk_name),
         }
     }
-    pub fn exec_nowait(&self, source: &'static str) -> Result<(), Error> {
+    pub fn exec_nowait(&self, source: &str) -> Result<(), Error> {
+        self._exec_nowait(source, None)
+    }
+    pub fn exec_named_nowait(&self, source: &str, chunk_name: &str) -> Result<(), Error> {
+        self._exec_nowait(source, Some(chunk_name))
+    }
+    fn _exec_nowait(&self, source: &str, chunk_name: Option<&str>) -> Result<(), Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
+                let (source, chunk_name) = (source.to_string(), chunk_name.map(str::to_string));
                 _handler.lock().unwrap().post(RawFunc::new(move || {
-                    let _ = Self::_exec(&lua.clone(), source);
+                    let _ = Self::_exec(&lua.clone(), &source, chunk_name.as_deref());
                 }));
             }
             None => {
-                Self::_exec(&self.lua.clone(), source)?;
+                Self::_exec(&self.lua.clone(), source, chunk_name)?;
             }
         }
 
         Ok(())
     }
     #[inline]
-    fn _exec(lua: &Arc<Mutex<Lua>>, source: &str) -> Result<LuaMessage, Error> {
-        lua.lock().unwrap().context(|lua| lua.load(source).eval())
+    fn _exec(
+        lua: &Arc<Mutex<Lua>>,
+        source: &str,
+        chunk_name: Option<&str>,
+    ) -> Result<LuaMessage, Error> {
+        lua.lock().unwrap().context(|lua| {
+            Self::load_chunk(lua, source, chunk_name)
+                .and_then(|chunk| chunk.eval())
+                .map_err(|err| ScriptError::locate(lua, err))
+        })
     }
     #[inline]
     pub fn exec_multi<'lua, R>(lua: Context<'lua>, source: &str) -> Result<R, Error>
     where
         R: FromLuaMulti<'lua>,
     {
-        lua.load(source).eval()
+        lua.load(source)
+            .eval()
+            .map_err(|err| ScriptError::locate(lua, err))
+    }
+    pub fn eval(&self, source: &str) -> Result<LuaMessage, Error> {
+        self._eval_named(source, None)
     }
-    pub fn eval(&self, source: &'static str) -> Result<LuaMessage, Error> {
+    /// Evaluate the source as a chunk named `chunk_name` (see `load_named`).
+    pub fn eval_named(&self, source: &str, chunk_name: &str) -> Result<LuaMessage, Error> {
+        self._eval_named(source, Some(chunk_name))
+    }
+    fn _eval_named(&self, source: &str, chunk_name: Option<&str>) -> Result<LuaMessage, Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
-                self.wait_async_lua_message_result(&_handler, move || Self::_eval(&lua, source))
+                let (source, chunk_name) = (source.to_string(), chunk_name.map(str::to_string));
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_eval(&lua, &source, chunk_name.as_deref())
+                })
             }
-            None => Self::_eval(&self.lua.clone(), source),
+            None => Self::_eval(&self.lua.clone(), source, chunk_name),
         }
     }
     #[inline]
-    fn _eval(lua: &Arc<Mutex<Lua>>, source: &str) -> Result<LuaMessage, Error> {
-        lua.lock().unwrap().context(|lua| lua.load(source).eval())
+    fn _eval(
+        lua: &Arc<Mutex<Lua>>,
+        source: &str,
+        chunk_name: Option<&str>,
+    ) -> Result<LuaMessage, Error> {
+        lua.lock().unwrap().context(|lua| {
+            Self::load_chunk(lua, source, chunk_name)
+                .and_then(|chunk| chunk.eval())
+                .map_err(|err| ScriptError::locate(lua, err))
+        })
     }
     #[inline]
     pub fn eval_multi<'lua, R>(lua: Context<'lua>, source: &str) -> Result<R, Error>
     where
         R: FromLuaMulti<'lua>,
     {
-        lua.load(source).eval()
+        lua.load(source)
+            .eval()
+            .map_err(|err| ScriptError::locate(lua, err))
+    }
+    /// Evaluate the source, keeping all of the values returned (like `call_multi`).
+    pub fn eval_all(&self, source: &str) -> Result<MultiLuaMessage, Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                let source = source.to_string();
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_eval_all(&lua, &source)
+                })
+            }
+            None => Self::_eval_all(&self.lua.clone(), source),
+        }
+    }
+    #[inline]
+    fn _eval_all(lua: &Arc<Mutex<Lua>>, source: &str) -> Result<MultiLuaMessage, Error> {
+        lua.lock()
+            .unwrap()
+            .context(|lua| Self::eval_multi(lua, source))
+    }
+
+    /// Compile the source once, keeping the function in the registry to `run` it later.
+    ///
+    /// Compiling the same name & source again returns the cached chunk,
+    /// while another source replaces the chunk of that name.
+    pub fn compile(&self, name: &str, source: &str) -> Result<ChunkId, Error> {
+        self.compile_with(name, source.as_bytes(), |lua, name, source| {
+            let source = String::from_utf8_lossy(source);
+            Self::load_named(lua, &source, name)?.into_function()
+        })
+    }
+    /// Load a chunk dumped by `dump` (possibly from another actor).
+    ///
+    /// # Safety
+    ///
+    /// Lua doesn't verify bytecode: malformed or malicious bytecode could crash the process
+    /// or corrupt its memory. The caller must only load the dumps of trusted actors
+    /// (built against the same Lua version), never bytecode from untrusted sources.
+    pub unsafe fn load_bytecode(&self, name: &str, bytecode: &[u8]) -> Result<ChunkId, Error> {
+        self.compile_with(name, bytecode, |lua, name, bytecode| {
+            let load = Self::builtin(lua, "load")?;
+            let bytecode = lua.create_string(bytecode)?;
+            let name = format!("@{}", name);
+            match load.call::<_, (Option<Function>, Option<String>)>((bytecode, name, "b"))? {
+                (Some(func), _) => Ok(func),
+                (None, err) => Err(Error::RuntimeError(err.unwrap_or_default())),
+            }
+        })
+    }
+    fn compile_with(
+        &self,
+        name: &str,
+        source: &[u8],
+        compile: impl for<'lua> Fn(Context<'lua>, &str, &[u8]) -> Result<Function<'lua>, Error>
+            + Send
+            + Sync
+            + Clone
+            + 'static,
+    ) -> Result<ChunkId, Error> {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(&(cached_hash, id)) = self.chunks.lock().unwrap().get(name) {
+            if cached_hash == hash {
+                return Ok(id);
+            }
+        }
+
+        let (lua, chunks) = (self.lua.clone(), self.chunks.clone());
+        let (name, source) = (name.to_string(), source.to_vec());
+        let func = move || -> Result<ChunkId, Error> {
+            lua.lock().unwrap().context(|lua| {
+                let func =
+                    compile(lua, &name, &source).map_err(|err| ScriptError::locate(lua, err))?;
+                let id = ChunkId(NEXT_CHUNK_ID.fetch_add(1, Ordering::SeqCst));
+                let table = Self::chunk_table(lua)?;
+                table.set(id.0, func)?;
+                if let Some((_, old)) = chunks.lock().unwrap().insert(name.clone(), (hash, id)) {
+                    table.set(old.0, Nil)?;
+                }
+                Ok(id)
+            })
+        };
+        match self.handler.clone() {
+            Some(_handler) => self.wait_async_lua_message_result(&_handler, func),
+            None => func(),
+        }
+    }
+    /// Run a compiled chunk; its arguments are available as `...` in the chunk.
+    pub fn run(
+        &self,
+        id: ChunkId,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Result<LuaMessage, Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_run(&lua, id, args.clone())
+                })
+            }
+            None => Self::_run(&self.lua.clone(), id, args),
+        }
+    }
+    pub fn run_nowait(
+        &self,
+        id: ChunkId,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Result<(), Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                _handler.lock().unwrap().post(RawFunc::new(move || {
+                    let _ = Self::_run(&lua.clone(), id, args.clone());
+                }));
+            }
+            None => {
+                Self::_run(&self.lua.clone(), id, args)?;
+            }
+        }
+        Ok(())
+    }
+    #[inline]
+    fn _run(
+        lua: &Arc<Mutex<Lua>>,
+        id: ChunkId,
+        args: impl Into<MultiLuaMessage>,
+    ) -> Result<LuaMessage, Error> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            Self::chunk_function(lua, id)?
+                .call(args.into())
+                .map_err(|err| ScriptError::locate(lua, err))
+        })
+    }
+    /// Dump a compiled chunk as Lua bytecode, to be loaded by `load_bytecode`.
+    pub fn dump(&self, id: ChunkId) -> Result<Vec<u8>, Error> {
+        let lua = self.lua.clone();
+        let func = move || -> Result<Vec<u8>, Error> {
+            lua.lock().unwrap().context(|lua| {
+                let dump = Self::builtin(lua, "dump")?;
+                let bytecode = dump.call::<_, rlua::String>(Self::chunk_function(lua, id)?)?;
+                Ok(bytecode.as_bytes().to_vec())
+            })
+        };
+        match self.handler.clone() {
+            Some(_handler) => self.wait_async_lua_message_result(&_handler, func),
+            None => func(),
+        }
+    }
+    fn keep_builtins(lua: Lua) -> Lua {
+        // Only fails out of memory, then `dump` & `load_bytecode` report the builtins missing.
+        let _ = lua.context(Self::keep_builtins_in);
+        lua
+    }
+    fn keep_builtins_in(lua: Context) -> Result<(), Error> {
+        let globals = lua.globals();
+        let builtins = lua.create_table()?;
+        builtins.set("load", globals.get::<_, Function>("load")?)?;
+        builtins.set(
+            "dump",
+            globals
+                .get::<_, Table>("string")?
+                .get::<_, Function>("dump")?,
+        )?;
+        lua.set_named_registry_value(BUILTINS_KEY, builtins)
+    }
+    fn builtin<'lua>(lua: Context<'lua>, name: &str) -> Result<Function<'lua>, Error> {
+        match lua.named_registry_value::<_, Value>(BUILTINS_KEY)? {
+            Value::Table(builtins) => builtins.get(name),
+            _ => Err(Error::RuntimeError(format!(
+                "the builtin '{}' is unavailable",
+                name
+            ))),
+        }
+    }
+    fn chunk_table(lua: Context) -> Result<Table, Error> {
+        match lua.named_registry_value::<_, Value>(CHUNKS_KEY)? {
+            Value::Table(t) => Ok(t),
+            _ => {
+                let t = lua.create_table()?;
+                lua.set_named_registry_value(CHUNKS_KEY, t.clone())?;
+                Ok(t)
+            }
+        }
+    }
+    fn chunk_function(lua: Context, id: ChunkId) -> Result<Function, Error> {
+        match Self::chunk_table(lua)?.get::<_, Value>(id.0)? {
+            Value::Function(func) => Ok(func),
+            _ => Err(Error::RuntimeError(format!("unknown chunk {:?}", id))),
+        }
     }
 
     pub fn call(
         &self,
-        name: &'static str,
+        name: &str,
         args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
     ) -> Result<LuaMessage, Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
+                let name = name.to_string();
                 self.wait_async_lua_message_result(&_handler, move || {
-                    Self::_call(&lua, name, args.clone())
+                    Self::_call(&lua, &name, args.clone())
                 })
             }
             None => Self::_call(&self.lua.clone(), name, args),
         }
     }
+    /// Call the function, keeping all of the values returned.
+    pub fn call_multi(
+        &self,
+        name: &str,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Result<MultiLuaMessage, Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                let name = name.to_string();
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_call_multi(&lua, &name, args.clone())
+                })
+            }
+            None => Self::_call_multi(&self.lua.clone(), name, args),
+        }
+    }
+    /// Call the Lua function converting the serde types, e.g. `call_typed::<_, Order>("place", (item, 2))`.
+    ///
+    /// Tuples of `args` are many arguments (`()` is none), and many results make a tuple of `Ret`;
+    /// conversion errors tell the path of the bad value (see `message::MessageError`).
+    #[cfg(feature = "serde")]
+    pub fn call_typed<Args: Serialize, Ret: DeserializeOwned>(
+        &self,
+        name: &str,
+        args: Args,
+    ) -> Result<Ret, Error> {
+        let args = to_multi_message(&args).map_err(|err| Error::ToLuaConversionError {
+            from: any::type_name::<Args>(),
+            to: "values",
+            message: Some(err.to_string()),
+        })?;
+        let mut results = Vec::<LuaMessage>::from(self.call_multi(name, args)?);
+        let results = match results.len() {
+            0 => LuaMessage::Nil,
+            1 => results.remove(0),
+            _ => LuaMessage::Array(results.into()),
+        };
+        from_message(results).map_err(|err| Error::FromLuaConversionError {
+            from: "values",
+            to: any::type_name::<Ret>(),
+            message: Some(err.to_string()),
+        })
+    }
+    #[inline]
+    fn _call_multi(
+        lua: &Arc<Mutex<Lua>>,
+        name: &str,
+        args: impl Into<MultiLuaMessage>,
+    ) -> Result<MultiLuaMessage, Error> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            Self::call_path(lua, name, args.into()).map_err(|err| ScriptError::locate(lua, err))
+        })
+    }
     pub fn call_nowait(
         &self,
-        name: &'static str,
+        name: &str,
         args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
     ) -> Result<(), Error> {
         match self.handler.clone() {
             Some(_handler) => {
                 let lua = self.lua.clone();
+                let name = name.to_string();
                 _handler.lock().unwrap().post(RawFunc::new(move || {
-                    let _ = Self::_call(&lua.clone(), name, args.clone());
+                    let _ = Self::_call(&lua.clone(), &name, args.clone());
                 }));
             }
             None => {
@@ -323,57 +837,666 @@ impl Actor {
     ) -> Result<LuaMessage, Error> {
         let vm = lua.lock().unwrap();
         vm.context(|lua| {
-            lua.globals()
-                .get::<_, Function>(name)
-                .unwrap()
+            Self::call_path(lua, name, args.into()).map_err(|err| ScriptError::locate(lua, err))
+        })
+    }
+
+    /// Call a Lua function previously returned from this actor.
+    pub fn call_function(
+        &self,
+        func: &LuaFunctionRef,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Result<LuaMessage, Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                let func = func.clone();
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_call_function(&lua, &func, args.clone())
+                })
+            }
+            None => Self::_call_function(&self.lua.clone(), func, args),
+        }
+    }
+    pub fn call_function_nowait(
+        &self,
+        func: &LuaFunctionRef,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Result<(), Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                let func = func.clone();
+                _handler.lock().unwrap().post(RawFunc::new(move || {
+                    let _ = Self::_call_function(&lua.clone(), &func, args.clone());
+                }));
+            }
+            None => {
+                Self::_call_function(&self.lua.clone(), func, args)?;
+            }
+        }
+        Ok(())
+    }
+    #[inline]
+    fn _call_function(
+        lua: &Arc<Mutex<Lua>>,
+        func: &LuaFunctionRef,
+        args: impl Into<MultiLuaMessage>,
+    ) -> Result<LuaMessage, Error> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            func.function(lua)?
                 .call::<_, _>(args.into())
+                .map_err(|err| ScriptError::locate(lua, err))
         })
     }
-    /*
+    /// Release a Lua function handle, along with the dropped ones not released yet.
+    pub fn release_function(&self, func: LuaFunctionRef) -> Result<(), Error> {
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                self.wait_async_lua_message_result(&_handler, move || {
+                    Self::_release_function(&lua, func.clone()).map(|_| LuaMessage::Nil)
+                })
+                .map(|_| ())
+            }
+            None => Self::_release_function(&self.lua.clone(), func),
+        }
+    }
     #[inline]
-    pub fn call_variadic<'lua, A, R>(lua: Context<'lua>, name: &str, args: A) -> Result<R, Error>
-    where
-        A: ToLuaMulti<'lua> + Send + Sync + Clone + 'static,
-        R: FromLuaMulti<'lua>,
-    {
-        let func: Function = lua.globals().get::<_, Function>(name)?;
+    fn _release_function(lua: &Arc<Mutex<Lua>>, func: LuaFunctionRef) -> Result<(), Error> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            func.release(lua)?;
+            lua.expire_registry_values();
+            Ok(())
+        })
+    }
 
-        func.call::<_, R>(args)
+    /// Serve the Debug Adapter Protocol on the address (e.g. "127.0.0.1:8172") for editors.
+    ///
+    /// Breakpoints are matched against chunk names (see `load_named`),
+    /// and the debug library is loaded (hidden from scripts) for it (see `hook::load_debug_lib`).
+    #[cfg(feature = "debugger")]
+    pub fn debug(&self, addr: impl ToSocketAddrs) -> Result<Debugger, Error> {
+        self.load_debug_lib()?;
+        let debugger = Debugger::listen(addr)?;
+        let hooks = self.hooks.clone();
+        let hook = Arc::new(Mutex::new(Some(debugger.hook())));
+        let mask = HookMask {
+            lines: true,
+            ..Default::default()
+        };
+        let installed = self.with_lua(move |lua| match hook.lock().unwrap().take() {
+            Some(hook) => hooks.set(lua, debugger::HOOK_NAME, mask, hook),
+            None => Ok(()),
+        });
+        if let Err(err) = installed {
+            debugger.stop();
+            return Err(err);
+        }
+        Ok(debugger)
+    }
+    /// Stop the debugger, resuming the actor if it's paused.
+    #[cfg(feature = "debugger")]
+    pub fn stop_debugger(&self, debugger: Debugger) -> Result<(), Error> {
+        debugger.stop();
+        let hooks = self.hooks.clone();
+        self.with_lua(move |lua| hooks.remove(lua, debugger::HOOK_NAME))
     }
-    // */
-}
 
-#[test]
-fn test_actor_new() {
-    use rlua::Variadic;
-    // use std::iter::FromIterator;
+    /// Sample the stack every `instructions` VM instructions, until `stop_profiler`.
+    pub fn start_profiler(&self, instructions: u32) -> Result<Profiler, Error> {
+        self.load_debug_lib()?;
+        let profiler = Profiler::default();
+        let hooks = self.hooks.clone();
+        let hook = Arc::new(Mutex::new(Some(profiler.hook())));
+        let mask = HookMask {
+            count: Some(instructions.max(1)),
+            ..Default::default()
+        };
+        self.with_lua(move |lua| match hook.lock().unwrap().take() {
+            Some(hook) => hooks.set(lua, profiler::HOOK_NAME, mask, hook),
+            None => Ok(()),
+        })?;
+        Ok(profiler)
+    }
+    /// Stop the profiler, returning the profile sampled.
+    pub fn stop_profiler(&self, profiler: Profiler) -> Result<Profile, Error> {
+        let hooks = self.hooks.clone();
+        self.with_lua(move |lua| hooks.remove(lua, profiler::HOOK_NAME))?;
+        Ok(profiler.snapshot())
+    }
 
-    fn test_actor(act: Actor) {
-        let _ = act.exec_nowait(
-            r#"
-            i = 1
-        "#,
-        );
-        assert_eq!(Some(1), Option::from(act.get_global("i").ok().unwrap()));
+    /// Count the lines run by the named chunks into the coverage, until `stop_coverage`.
+    ///
+    /// The same coverage could be started on many actors.
+    pub fn start_coverage(&self, coverage: &Coverage) -> Result<(), Error> {
+        self.load_debug_lib()?;
+        let hooks = self.hooks.clone();
+        let hook = Arc::new(Mutex::new(Some(coverage.hook())));
+        let mask = HookMask {
+            calls: true,
+            lines: true,
+            ..Default::default()
+        };
+        self.with_lua(move |lua| match hook.lock().unwrap().take() {
+            Some(hook) => hooks.set(lua, coverage::HOOK_NAME, mask, hook),
+            None => Ok(()),
+        })
+    }
+    /// Stop counting the lines run.
+    pub fn stop_coverage(&self) -> Result<(), Error> {
+        let hooks = self.hooks.clone();
+        self.with_lua(move |lua| hooks.remove(lua, coverage::HOOK_NAME))
+    }
 
-        let v = act.eval(
-            r#"
-            3
-        "#,
-        );
-        assert_eq!(Some(3), Option::from(v.ok().unwrap()));
+    #[inline]
+    fn load_debug_lib(&self) -> Result<(), Error> {
+        hook::load_debug_lib(&self.lua.lock().unwrap())
+    }
 
-        act.exec(
-            r#"
-            function testit (i)
-                local Object = require("src.test")
-                return Object:calc1(i)
-            end
-        "#,
-        )
-        .ok()
-        .unwrap();
-        match act.call("testit", 1) {
+    /// Set the limits of the conversions of Lua values to `LuaMessage` (see `ConversionOptions`).
+    pub fn set_conversion_options(&self, options: ConversionOptions) -> Result<(), Error> {
+        self.with_lua(move |lua| message::set_conversion_options(lua, options))
+    }
+
+    /// Build a batch of operations run back-to-back in one job (see `Batch`).
+    pub fn batch(&self) -> Batch {
+        Batch {
+            actor: self.clone(),
+            ops: vec![],
+            atomic: false,
+        }
+    }
+
+    /// Define `emit(topic, payload)`, `on(pattern, func) -> id` & `off(id)` for Lua scripts.
+    ///
+    /// Events emitted by Lua or published by Rust are delivered on the handler
+    /// to every matching Rust subscriber & Lua handler (called with `payload, topic`).
+    /// The errors of the Lua handlers are raised by `emit` (returned by `publish` without a
+    /// handler) once all of them ran.
+    pub fn enable_events(&self) -> Result<(), Error> {
+        self.lua
+            .lock()
+            .unwrap()
+            .context(|lua| self.events.def_lua_fns(lua))
+    }
+    /// Subscribe to the events matching the topic pattern (see `event::topic_matches`),
+    /// called with the topic of each event and its payload.
+    ///
+    /// Subscribers are called while the Lua VM is locked,
+    /// so they should only call the `_nowait` methods of an actor with a handler.
+    pub fn subscribe(
+        &self,
+        pattern: &str,
+        func: impl FnMut(&str, LuaMessage) + Send + Sync + 'static,
+    ) -> EventSubscription {
+        self.events.subscribe(pattern, func)
+    }
+    pub fn unsubscribe(&self, subscription: EventSubscription) {
+        self.events.unsubscribe(subscription)
+    }
+    pub fn publish(&self, topic: &str, payload: LuaMessage) -> Result<(), Error> {
+        let topic = topic.to_string();
+        match self.handler.clone() {
+            Some(_handler) => {
+                let lua = self.lua.clone();
+                let events = self.events.clone();
+                _handler.lock().unwrap().post(RawFunc::new(move || {
+                    let _ = lua
+                        .lock()
+                        .unwrap()
+                        .context(|lua| events.dispatch(lua, &topic, payload.clone()));
+                }));
+            }
+            None => {
+                self.lua
+                    .lock()
+                    .unwrap()
+                    .context(|lua| self.events.dispatch(lua, &topic, payload))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Define a global Rust function returning a `Future`, callable from Lua coroutines.
+    ///
+    /// Calling it suspends the calling coroutine until the future completes, so the handler
+    /// keeps processing other jobs meanwhile. The coroutine is resumed on the handler with the
+    /// result (or the error raised inside Lua). The futures are polled on the handler thread
+    /// whenever they are woken (or on the waking thread, without a handler).
+    ///
+    /// Coroutines should be started with `spawn`; calling it from the main chunk (or across a
+    /// `pcall`, which is not yieldable in rlua) is an error.
+    pub fn def_async_fn<F, Fut>(&self, key: &'static str, func: F) -> Result<(), Error>
+    where
+        F: 'static + Send + Sync + Fn(Vec<LuaMessage>) -> Fut,
+        Fut: 'static + Send + Future<Output = Result<LuaMessage, Error>>,
+    {
+        let handler = self.handler.clone();
+        // Weak, as the Lua VM owns this closure.
+        let lua_vm = Arc::downgrade(&self.lua);
+
+        self.lua.lock().unwrap().context(|lua| {
+            let start = lua.create_function(move |lua, args: Variadic<LuaMessage>| {
+                let id = NEXT_PENDING_ID.fetch_add(1, Ordering::SeqCst);
+                let task = Arc::new(AsyncTask {
+                    id,
+                    future: Mutex::new(Some(Box::pin(func(args.into_iter().collect())))),
+                    woken: AtomicBool::new(false),
+                    handler: handler.clone(),
+                    lua: lua_vm.clone(),
+                });
+                // The futures ready at once don't suspend the coroutine.
+                match task.poll() {
+                    Some(Ok(value)) => Ok((true, true, value)),
+                    Some(Err(err)) => Ok((true, false, LuaMessage::from(err.to_string()))),
+                    None => {
+                        Self::pending_coroutines(lua)?.set(id, lua.current_thread())?;
+                        Ok((false, false, LuaMessage::Nil))
+                    }
+                }
+            })?;
+            let wrapper = lua
+                .load(ASYNC_FN_WRAPPER)
+                .set_name("=async_fn")?
+                .call::<_, Function>(start)?;
+            lua.globals().set(key, wrapper)
+        })
+    }
+    /// Call a global function inside a new coroutine, so it could wait for async functions.
+    ///
+    /// The returned `Spawned` gives the value returned by the coroutine, or its error
+    /// (e.g. the function is missing, or it raised an error after waiting).
+    pub fn spawn(
+        &self,
+        name: &str,
+        args: impl Into<MultiLuaMessage> + Clone + Sync + Send + 'static,
+    ) -> Spawned {
+        let spawned = Spawned::default();
+        let lua = self.lua.clone();
+        let name = name.to_string();
+        let done = spawned.clone();
+        Self::post_or_run(&self.handler, move || {
+            Self::_spawn(&lua, &name, args.clone(), done.clone());
+        });
+        spawned
+    }
+    #[inline]
+    fn _spawn(
+        lua: &Arc<Mutex<Lua>>,
+        name: &str,
+        args: impl Into<MultiLuaMessage>,
+        spawned: Spawned,
+    ) {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            let thread = match Self::get_path::<Function>(lua, name)
+                .and_then(|func| lua.create_thread(func))
+                .and_then(|thread| {
+                    Self::spawned_coroutines(lua)?.raw_set(thread.clone(), spawned.clone())?;
+                    Ok(thread)
+                }) {
+                Ok(thread) => thread,
+                Err(err) => return spawned.finish(Err(ScriptError::locate(lua, err))),
+            };
+            let result = thread.resume::<_, LuaMessage>(args.into());
+            let _ = Self::settle(lua, thread, result);
+        })
+    }
+    #[inline]
+    fn _resume_pending(
+        lua: &Arc<Mutex<Lua>>,
+        id: usize,
+        result: Result<LuaMessage, Error>,
+    ) -> Result<(), Error> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            let pending = Self::pending_coroutines(lua)?;
+            let thread = pending.get::<_, Thread>(id)?;
+            pending.set(id, Nil)?;
+            let resumed = match result {
+                Ok(value) => thread.resume::<_, LuaMessage>((true, value)),
+                Err(err) => thread.resume::<_, LuaMessage>((false, err.to_string())),
+            };
+            Self::settle(lua, thread, resumed)
+        })
+    }
+    // Report the end of a coroutine to its `Spawned` (unless it's waiting again);
+    // the errors of the coroutines not started by `spawn` are returned.
+    fn settle<'lua>(
+        lua: Context<'lua>,
+        thread: Thread<'lua>,
+        result: Result<LuaMessage, Error>,
+    ) -> Result<(), Error> {
+        if result.is_ok() && thread.status() == ThreadStatus::Resumable {
+            return Ok(());
+        }
+        let result = result.map_err(|err| ScriptError::locate(lua, err));
+        let spawned = Self::spawned_coroutines(lua)?;
+        match spawned.raw_get::<_, Option<AnyUserData>>(thread.clone())? {
+            Some(done) => {
+                spawned.raw_set(thread, Nil)?;
+                done.borrow::<Spawned>()?.finish(result);
+                Ok(())
+            }
+            None => result.map(|_| ()),
+        }
+    }
+    fn spawned_coroutines(lua: Context) -> Result<Table, Error> {
+        match lua.named_registry_value::<_, Value>(SPAWNED_COROUTINES_KEY)? {
+            Value::Table(t) => Ok(t),
+            _ => {
+                let t = lua.create_table()?;
+                lua.set_named_registry_value(SPAWNED_COROUTINES_KEY, t.clone())?;
+                Ok(t)
+            }
+        }
+    }
+    fn pending_coroutines(lua: Context) -> Result<Table, Error> {
+        match lua.named_registry_value::<_, Value>(PENDING_COROUTINES_KEY)? {
+            Value::Table(t) => Ok(t),
+            _ => {
+                let t = lua.create_table()?;
+                lua.set_named_registry_value(PENDING_COROUTINES_KEY, t.clone())?;
+                Ok(t)
+            }
+        }
+    }
+    #[inline]
+    fn post_or_run(
+        handler: &Option<Arc<Mutex<HandlerThread>>>,
+        func: impl FnMut() + Send + Sync + 'static,
+    ) {
+        match handler {
+            Some(_handler) => _handler.lock().unwrap().post(RawFunc::new(func)),
+            None => RawFunc::new(func).invoke(),
+        }
+    }
+    /*
+    #[inline]
+    pub fn call_variadic<'lua, A, R>(lua: Context<'lua>, name: &str, args: A) -> Result<R, Error>
+    where
+        A: ToLuaMulti<'lua> + Send + Sync + Clone + 'static,
+        R: FromLuaMulti<'lua>,
+    {
+        let func: Function = lua.globals().get::<_, Function>(name)?;
+
+        func.call::<_, R>(args)
+    }
+    // */
+}
+
+#[derive(Clone)]
+enum BatchOp {
+    SetGlobal(String, LuaMessage),
+    Exec(String, Option<String>),
+    Call(String, MultiLuaMessage),
+    GetGlobal(String),
+}
+
+/// Operations run back-to-back in one job of an `Actor`, built by `Actor::batch`.
+///
+/// Each operation gives one result (`Nil` for `set_global`, the value otherwise).
+/// In atomic mode, the first failure restores the global variables (shallowly) as they were
+/// before the batch, and the paths written by `set_global` (e.g. `cfg.x`): the operations
+/// done before are rolled back, and the remaining ones skipped, all with an error.
+#[derive(Clone)]
+pub struct Batch {
+    actor: Actor,
+    ops: Vec<BatchOp>,
+    atomic: bool,
+}
+
+impl Batch {
+    pub fn set_global(mut self, key: &str, value: LuaMessage) -> Self {
+        self.ops.push(BatchOp::SetGlobal(key.to_string(), value));
+        self
+    }
+    pub fn exec(mut self, source: &str) -> Self {
+        self.ops.push(BatchOp::Exec(source.to_string(), None));
+        self
+    }
+    pub fn exec_named(mut self, source: &str, chunk_name: &str) -> Self {
+        self.ops.push(BatchOp::Exec(
+            source.to_string(),
+            Some(chunk_name.to_string()),
+        ));
+        self
+    }
+    pub fn call(mut self, name: &str, args: impl Into<MultiLuaMessage>) -> Self {
+        self.ops.push(BatchOp::Call(name.to_string(), args.into()));
+        self
+    }
+    pub fn get_global(mut self, key: &str) -> Self {
+        self.ops.push(BatchOp::GetGlobal(key.to_string()));
+        self
+    }
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    pub fn run(self) -> Vec<Result<LuaMessage, Error>> {
+        let (ops, atomic) = (self.ops, self.atomic);
+        match self.actor.handler.clone() {
+            Some(_handler) => {
+                let lua = self.actor.lua.clone();
+                self.actor
+                    .wait_async_lua_message_result(&_handler, move || {
+                        Self::_run(&lua, ops.clone(), atomic)
+                    })
+            }
+            None => Self::_run(&self.actor.lua, ops, atomic),
+        }
+    }
+    fn _run(
+        lua: &Arc<Mutex<Lua>>,
+        ops: Vec<BatchOp>,
+        atomic: bool,
+    ) -> Vec<Result<LuaMessage, Error>> {
+        let vm = lua.lock().unwrap();
+        vm.context(|lua| {
+            let globals = lua.globals();
+            let snapshot = if atomic {
+                match globals
+                    .clone()
+                    .pairs::<Value, Value>()
+                    .collect::<Result<Vec<_>, Error>>()
+                {
+                    Ok(snapshot) => Some(snapshot),
+                    Err(err) => return ops.iter().map(|_| Err(err.clone())).collect(),
+                }
+            } else {
+                None
+            };
+
+            // The paths written, with their former values.
+            let mut writes = Vec::<(String, Value)>::new();
+            let mut results = Vec::with_capacity(ops.len());
+            let mut failed = false;
+            for op in ops {
+                if failed {
+                    results.push(Err(Error::RuntimeError(
+                        "skipped as an earlier operation of the atomic batch failed".to_string(),
+                    )));
+                    continue;
+                }
+                let result = match op {
+                    BatchOp::SetGlobal(key, value) => {
+                        if atomic {
+                            if let Ok(former) = Actor::get_path::<Value>(lua, &key) {
+                                writes.push((key.clone(), former));
+                            }
+                        }
+                        Actor::set_path(lua, &key, value).map(|_| LuaMessage::Nil)
+                    }
+                    BatchOp::Exec(source, chunk_name) => {
+                        Actor::load_chunk(lua, &source, chunk_name.as_deref())
+                            .and_then(|chunk| chunk.eval())
+                            .map_err(|err| ScriptError::locate(lua, err))
+                    }
+                    BatchOp::Call(name, args) => Actor::call_path(lua, &name, args)
+                        .map_err(|err| ScriptError::locate(lua, err)),
+                    BatchOp::GetGlobal(key) => Actor::get_path(lua, &key),
+                };
+                if result.is_err() {
+                    if let Some(ref snapshot) = snapshot {
+                        failed = true;
+                        for (key, former) in writes.drain(..).rev() {
+                            let _ = Actor::set_path(lua, &key, former);
+                        }
+                        let _ = Self::restore_globals(&globals, snapshot);
+                        for done in results.iter_mut().filter(|done| done.is_ok()) {
+                            *done = Err(Error::RuntimeError(
+                                "rolled back as a later operation of the atomic batch failed"
+                                    .to_string(),
+                            ));
+                        }
+                    }
+                }
+                results.push(result);
+            }
+            results
+        })
+    }
+    fn restore_globals<'lua>(
+        globals: &Table<'lua>,
+        snapshot: &[(Value<'lua>, Value<'lua>)],
+    ) -> Result<(), Error> {
+        let current = globals
+            .clone()
+            .pairs::<Value, Value>()
+            .collect::<Result<Vec<_>, Error>>()?;
+        for (key, _) in current {
+            globals.raw_set(key, Nil)?;
+        }
+        for (key, value) in snapshot {
+            globals.raw_set(key.clone(), value.clone())?;
+        }
+        Ok(())
+    }
+}
+
+type AsyncResult = Result<LuaMessage, Error>;
+
+/// The completion of a coroutine started by `Actor::spawn`.
+#[derive(Clone, Default)]
+pub struct Spawned {
+    done: Arc<(Mutex<Option<AsyncResult>>, Condvar)>,
+}
+
+impl Spawned {
+    /// The value returned by the coroutine (or its error), once it's finished.
+    pub fn result(&self) -> Option<Result<LuaMessage, Error>> {
+        self.done.0.lock().unwrap().clone()
+    }
+    /// Wait for the coroutine to finish.
+    pub fn wait(&self) -> Result<LuaMessage, Error> {
+        let (result, finished) = &*self.done;
+        let mut result = result.lock().unwrap();
+        loop {
+            match *result {
+                Some(ref result) => return result.clone(),
+                None => result = finished.wait(result).unwrap(),
+            }
+        }
+    }
+    /// Wait for the coroutine to finish, up to the timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<LuaMessage, Error>> {
+        let (result, finished) = &*self.done;
+        let result = result.lock().unwrap();
+        let (result, _) = finished
+            .wait_timeout_while(result, timeout, |result| result.is_none())
+            .unwrap();
+        result.clone()
+    }
+
+    fn finish(&self, result: Result<LuaMessage, Error>) {
+        let (done, finished) = &*self.done;
+        *done.lock().unwrap() = Some(result);
+        finished.notify_all();
+    }
+}
+
+impl UserData for Spawned {}
+
+// The future of a call of an async function, polled whenever it's woken.
+struct AsyncTask {
+    id: usize,
+    future: Mutex<Option<Pin<Box<dyn Future<Output = AsyncResult> + Send>>>>,
+    // Woken while being polled: poll again.
+    woken: AtomicBool,
+    handler: Option<Arc<Mutex<HandlerThread>>>,
+    lua: Weak<Mutex<Lua>>,
+}
+
+impl AsyncTask {
+    // The result once ready (only the first time).
+    fn poll(self: &Arc<Self>) -> Option<AsyncResult> {
+        self.woken.store(true, Ordering::SeqCst);
+        let waker = Waker::from(self.clone());
+        let mut cx = TaskContext::from_waker(&waker);
+        loop {
+            // Being polled by another thread, which sees it woken and polls again.
+            let mut future = self.future.try_lock().ok()?;
+            self.woken.store(false, Ordering::SeqCst);
+            if let Poll::Ready(result) = future.as_mut()?.as_mut().poll(&mut cx) {
+                *future = None;
+                return Some(result);
+            }
+            drop(future);
+            if !self.woken.load(Ordering::SeqCst) {
+                return None;
+            }
+        }
+    }
+}
+
+impl Wake for AsyncTask {
+    fn wake(self: Arc<Self>) {
+        let task = self.clone();
+        Actor::post_or_run(&self.handler, move || {
+            if let (Some(result), Some(lua)) = (task.poll(), task.lua.upgrade()) {
+                let _ = Actor::_resume_pending(&lua, task.id, result);
+            }
+        });
+    }
+}
+
+#[test]
+#[allow(clippy::bool_assert_comparison, clippy::let_unit_value)]
+fn test_actor_new() {
+    use rlua::Variadic;
+    // use std::iter::FromIterator;
+
+    fn test_actor(act: Actor) {
+        let _ = act.exec_nowait(
+            r#"
+            i = 1
+        "#,
+        );
+        assert_eq!(Some(1), Option::from(act.get_global("i").ok().unwrap()));
+
+        let v = act.eval(
+            r#"
+            3
+        "#,
+        );
+        assert_eq!(Some(3), Option::from(v.ok().unwrap()));
+
+        act.exec(
+            r#"
+            function testit (i)
+                local Object = require("src.test")
+                return Object:calc1(i)
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+        match act.call("testit", 1) {
             Ok(_v) => {
                 assert_eq!(Some(2), Option::from(_v));
             }
@@ -500,3 +1623,683 @@ fn test_actor_new() {
     let _ = test_actor(Actor::new_with_handler(None));
     let _ = test_actor(Actor::new());
 }
+
+#[test]
+fn test_actor_async_fn() {
+    use std::time::Instant;
+
+    struct Delay(Instant, Result<LuaMessage, Error>);
+    impl Future for Delay {
+        type Output = Result<LuaMessage, Error>;
+        fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Self::Output> {
+            if Instant::now() >= self.0 {
+                return Poll::Ready(self.1.clone());
+            }
+            let (deadline, waker) = (self.0, cx.waker().clone());
+            thread::spawn(move || {
+                thread::sleep(deadline - Instant::now());
+                waker.wake();
+            });
+            Poll::Pending
+        }
+    }
+
+    fn test_actor(act: Actor) {
+        act.def_async_fn("fetch", |args| {
+            let deadline = Instant::now() + Duration::from_millis(50);
+            match Option::<i64>::from(args[0].clone()) {
+                Some(i) => Delay(deadline, Ok(LuaMessage::from(i + 1))),
+                None => Delay(deadline, Err(Error::RuntimeError("not a number".into()))),
+            }
+        })
+        .ok()
+        .unwrap();
+        act.exec(
+            r#"
+            function worker (i)
+                result = fetch(i)
+                return result
+            end
+            function failing_worker ()
+                failure = "raised"
+                fetch("x")
+                failure = "returned"
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        let worker = act.spawn("worker", 41);
+        // The actor is not blocked while the future is pending.
+        assert_eq!(Some(2), Option::from(act.eval("1 + 1").ok().unwrap()));
+        assert_eq!(LuaMessage::Nil, act.get_global("result").ok().unwrap());
+        assert_eq!(Some(42), Option::from(worker.wait().ok().unwrap()));
+        assert_eq!(
+            Some(42),
+            Option::from(act.get_global("result").ok().unwrap())
+        );
+
+        let failing = act.spawn("failing_worker", ());
+        let err = failing.wait().err().unwrap();
+        assert!(err.to_string().contains("not a number"), "{}", err);
+        assert_eq!(
+            Some("raised".to_string()),
+            Option::from(act.get_global("failure").ok().unwrap())
+        );
+
+        // Ready futures don't suspend the coroutine.
+        act.def_async_fn("ready", |args| {
+            let value = args.into_iter().next().unwrap_or(LuaMessage::Nil);
+            std::future::ready(Ok(value))
+        })
+        .ok()
+        .unwrap();
+        act.exec("function echo (x) return ready(x) end")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            Some(7),
+            Option::from(act.spawn("echo", 7).wait().ok().unwrap())
+        );
+
+        assert!(act.spawn("missing", ()).wait().is_err());
+        assert!(act
+            .spawn("worker", 1)
+            .wait_timeout(Duration::from_secs(5))
+            .is_some());
+
+        assert!(act.eval("fetch(1)").is_err());
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_function_ref() {
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            function make_adder (n)
+                return function (x) return x + n end
+            end
+            function apply (f, x)
+                return f(x)
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        let add3 = Option::<LuaFunctionRef>::from(act.call("make_adder", 3).ok().unwrap()).unwrap();
+        assert_eq!(
+            Some(7),
+            Option::from(act.call_function(&add3, 4).ok().unwrap())
+        );
+        assert_eq!(
+            Some(8),
+            Option::from(act.call("apply", (add3.clone(), 5)).ok().unwrap())
+        );
+
+        act.release_function(add3).ok().unwrap();
+
+        // The dropped handles are removed from the registry by the next ones.
+        act.exec(
+            r#"
+            weak = setmetatable({}, {__mode = "k"})
+            function tracked (v) weak[v] = true return v end
+            function alive ()
+                collectgarbage()
+                local n = 0
+                for _ in pairs(weak) do n = n + 1 end
+                return n
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+        let func = act.eval("tracked(function () end)").ok().unwrap();
+        let thread = act.eval("tracked(coroutine.create(print))");
+        assert_eq!(Some(2), Option::from(act.call("alive", ()).ok().unwrap()));
+        drop((func, thread));
+        let kept = act.eval("tracked(function () end)").ok().unwrap();
+        assert_eq!(Some(1), Option::from(act.call("alive", ()).ok().unwrap()));
+        drop(kept);
+
+        // Handles are bound to the VM which created them.
+        let other = Actor::new_with_handler(None).eval("function () end");
+        let other = Option::<LuaFunctionRef>::from(other.ok().unwrap()).unwrap();
+        assert!(act.call_function(&other, ()).is_err());
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_events() {
+    fn test_actor(act: Actor) {
+        act.enable_events().ok().unwrap();
+        act.exec(
+            r#"
+            received = 0
+            handler_id = on("price.*", function (payload, topic)
+                received = received + payload
+                last_topic = topic
+            end)
+            function create_order (id)
+                emit("order.created", id)
+                emit("user.created", -1)
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        let orders = Arc::new(Mutex::new(Vec::<LuaMessage>::new()));
+        let orders2 = orders.clone();
+        let subscription =
+            act.subscribe("order.*", move |_, msg| orders2.lock().unwrap().push(msg));
+        let topics = Arc::new(Mutex::new(Vec::<String>::new()));
+        let topics2 = topics.clone();
+        act.subscribe("**", move |topic, _| {
+            topics2.lock().unwrap().push(topic.to_string())
+        });
+
+        act.call("create_order", 7).ok().unwrap();
+        assert_eq!(vec![LuaMessage::from(7)], *orders.lock().unwrap());
+        assert_eq!(
+            vec!["order.created".to_string(), "user.created".to_string()],
+            *topics.lock().unwrap()
+        );
+
+        act.publish("price.updated", LuaMessage::from(5))
+            .ok()
+            .unwrap();
+        act.publish("price.updated", LuaMessage::from(6))
+            .ok()
+            .unwrap();
+        act.publish("order.shipped", LuaMessage::from(8))
+            .ok()
+            .unwrap();
+        act.exec("off(handler_id)").ok().unwrap();
+        act.publish("price.updated", LuaMessage::from(100))
+            .ok()
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            Some(11),
+            Option::from(act.get_global("received").ok().unwrap())
+        );
+        assert_eq!(
+            Some("price.updated".to_string()),
+            Option::from(act.get_global("last_topic").ok().unwrap())
+        );
+
+        act.unsubscribe(subscription);
+        act.call("create_order", 9).ok().unwrap();
+        assert_eq!(
+            vec![LuaMessage::from(7), LuaMessage::from(8)],
+            *orders.lock().unwrap()
+        );
+
+        // A failing handler doesn't stop the others.
+        act.exec(
+            r#"
+            on("alarm", function () error("broken") end)
+            on("alarm", function () rang = true end)
+        "#,
+        )
+        .ok()
+        .unwrap();
+        let err = act.exec("emit('alarm', 1)").unwrap_err().to_string();
+        assert!(err.contains("event handlers of 'alarm' failed"), "{}", err);
+        assert!(err.contains("broken"), "{}", err);
+        assert_eq!(
+            Some(true),
+            Option::from(act.get_global("rang").ok().unwrap())
+        );
+
+        // The handlers of a topic run in registration order.
+        act.exec(
+            r#"
+            steps = {}
+            for i = 1, 20 do
+                on("step", function () table.insert(steps, i) end)
+            end
+            emit("step", 0)
+        "#,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            Some(
+                (1..=20)
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Option::from(act.eval("table.concat(steps, ',')").ok().unwrap())
+        );
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_batch() {
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            function add (a, b)
+                return a + b
+            end
+            counter = 1
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        let results = act
+            .batch()
+            .set_global("a", LuaMessage::from(2))
+            .exec("counter = counter + a")
+            .call("add", (LuaMessage::from(3), 4))
+            .get_global("counter")
+            .run();
+        assert_eq!(4, results.len());
+        assert_eq!(LuaMessage::Nil, *results[0].as_ref().unwrap());
+        assert_eq!(Some(7), Option::from(results[2].clone().unwrap()));
+        assert_eq!(Some(3), Option::from(results[3].clone().unwrap()));
+
+        // Not atomic: the failure doesn't affect the other operations.
+        let results = act
+            .batch()
+            .set_global("b", LuaMessage::from(1))
+            .call("missing", ())
+            .get_global("b")
+            .run();
+        assert!(results[1].is_err());
+        assert_eq!(Some(1), Option::from(results[2].clone().unwrap()));
+
+        let results = act
+            .batch()
+            .atomic(true)
+            .set_global("a", LuaMessage::from(100))
+            .exec("counter = 0; temp = true")
+            .exec("error('boom')")
+            .get_global("a")
+            .run();
+        assert!(results.iter().all(|result| result.is_err()));
+        assert!(results[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("rolled back"));
+        assert!(results[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("boom"));
+        assert_eq!(Some(2), Option::from(act.get_global("a").ok().unwrap()));
+        assert_eq!(
+            Some(3),
+            Option::from(act.get_global("counter").ok().unwrap())
+        );
+        assert_eq!(LuaMessage::Nil, act.get_global("temp").ok().unwrap());
+
+        // The nested paths written are restored too.
+        act.exec("cfg = { x = 1 }").ok().unwrap();
+        let results = act
+            .batch()
+            .atomic(true)
+            .set_global("cfg.x", LuaMessage::from(2))
+            .set_global("cfg.y", LuaMessage::from(3))
+            .set_global("cfg.x", LuaMessage::from(4))
+            .call("missing", ())
+            .run();
+        assert!(results.iter().all(|result| result.is_err()));
+        assert_eq!(Some(1), Option::from(act.get_global("cfg.x").ok().unwrap()));
+        assert_eq!(LuaMessage::Nil, act.get_global("cfg.y").ok().unwrap());
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_paths() {
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            mod = { sub = { n = 1 } }
+            function mod.sub.add (a, b) return a + b end
+            Counter = { n = 10 }
+            function Counter:incr (by) self.n = self.n + by return self.n end
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        assert_eq!(
+            Some(5),
+            Option::from(act.call("mod.sub.add", (2, 3)).ok().unwrap())
+        );
+        assert_eq!(
+            Some(15),
+            Option::from(act.call("Counter:incr", 5).ok().unwrap())
+        );
+        assert_eq!(
+            Some(1),
+            Option::from(act.get_global("mod.sub.n").ok().unwrap())
+        );
+        assert_eq!(
+            LuaMessage::Nil,
+            act.get_global("mod.sub.none").ok().unwrap()
+        );
+
+        act.set_global("mod.sub.n", LuaMessage::from(2))
+            .ok()
+            .unwrap();
+        assert_eq!(
+            Some(2),
+            Option::from(act.get_global("mod.sub.n").ok().unwrap())
+        );
+
+        let err = act.call("mod.missing.add", ()).err().unwrap().to_string();
+        assert!(err.contains("'mod.missing' is nil"), "{}", err);
+        let err = act.call("mod.sub.n.x", ()).err().unwrap().to_string();
+        assert!(err.contains("'mod.sub.n' is number"), "{}", err);
+        let err = act.call("mod.sub.none", ()).err().unwrap().to_string();
+        assert!(err.contains("it is nil"), "{}", err);
+        assert!(act.get_global("Counter:incr").is_err());
+        assert!(act.call("mod..add", ()).is_err());
+        for path in &[
+            "Counter:incr.x",
+            "Counter:a:b",
+            "mod:sub.add",
+            ":incr",
+            "mod.",
+        ] {
+            let err = act.call(path, ()).err().unwrap().to_string();
+            assert!(err.contains("invalid path"), "{}", err);
+            let err = act.get_global(path).err().unwrap().to_string();
+            assert!(err.contains("invalid path"), "{}", err);
+        }
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_multi() {
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            function lookup (key)
+                if key == "a" then
+                    return true, nil, { 1, 2 }
+                end
+                return false, "missing " .. key
+            end
+        "#,
+        )
+        .ok()
+        .unwrap();
+
+        let found = Vec::<LuaMessage>::from(act.call_multi("lookup", "a").ok().unwrap());
+        assert_eq!(
+            vec![
+                LuaMessage::from(true),
+                LuaMessage::Nil,
+                LuaMessage::from(vec![1, 2])
+            ],
+            found
+        );
+        let missing = act.call_multi("lookup", "b").ok().unwrap();
+        assert_eq!(2, missing.len());
+        assert_eq!(LuaMessage::from("missing b"), missing.get(1));
+
+        let values = act.eval_all("1, 'two', 3.0").ok().unwrap();
+        assert_eq!(3, values.len());
+        assert_eq!(LuaMessage::from(3.0), values.get(2));
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_chunks() {
+    fn test_actor(act: Actor) {
+        let add = act
+            .compile("add.lua", "local a, b = ... return a + b")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            add,
+            act.compile("add.lua", "local a, b = ... return a + b")
+                .ok()
+                .unwrap()
+        );
+        assert_eq!(Some(5), Option::from(act.run(add, (2, 3)).ok().unwrap()));
+
+        let mul = act
+            .compile("add.lua", "local a, b = ... return a * b")
+            .ok()
+            .unwrap();
+        assert_ne!(add, mul);
+        assert_eq!(Some(6), Option::from(act.run(mul, (2, 3)).ok().unwrap()));
+        assert!(act.run(add, (2, 3)).is_err());
+        assert!(act.compile("broken.lua", "return +").is_err());
+
+        // Scripts replacing the builtins don't change `dump` & `load_bytecode`.
+        act.exec("string.dump = nil load = nil").unwrap();
+        let bytecode = act.dump(mul).ok().unwrap();
+        let other = Actor::new();
+        other
+            .exec("load = function() error('replaced') end")
+            .unwrap();
+        let loaded = unsafe { other.load_bytecode("mul.luac", &bytecode) }
+            .ok()
+            .unwrap();
+        assert_eq!(
+            Some(12),
+            Option::from(other.run(loaded, (3, 4)).ok().unwrap())
+        );
+        assert!(unsafe { other.load_bytecode("text.lua", b"return 1") }.is_err());
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_script_errors() {
+    use error::ScriptError;
+
+    fn test_actor(act: Actor) {
+        let err = act
+            .exec_named(
+                r#"
+                function check(n)
+                    if n > 1 then
+                        error("too big: " .. n)
+                    end
+                    return n
+                end
+                check(2)
+            "#,
+                "scripts/check.lua",
+            )
+            .err()
+            .unwrap();
+        let located = ScriptError::find(&err).unwrap();
+        assert_eq!("scripts/check.lua", located.file);
+        assert_eq!(4, located.line);
+        assert_eq!("too big: 2", located.message);
+        assert_eq!(
+            Some(r#"error("too big: " .. n)"#),
+            located.source_line.as_deref()
+        );
+        assert!(located
+            .traceback
+            .as_ref()
+            .unwrap()
+            .contains("scripts/check.lua:8: in main chunk"));
+        assert!(err.to_string().contains("scripts/check.lua:4: too big: 2"));
+
+        // Functions of named chunks are located there when called later on.
+        let err = act.call("check", 3).err().unwrap();
+        assert_eq!(4, ScriptError::find(&err).unwrap().line);
+
+        let err = act.eval_named("\nreturn +", "broken.lua").err().unwrap();
+        let located = ScriptError::find(&err).unwrap();
+        assert_eq!(("broken.lua", 2), (located.file.as_str(), located.line));
+        assert_eq!(Some("return +"), located.source_line.as_deref());
+
+        let id = act
+            .compile("compiled.lua", "local t = ...\nreturn t.x")
+            .ok()
+            .unwrap();
+        let err = act.run(id, ()).err().unwrap();
+        let located = ScriptError::find(&err).unwrap();
+        assert_eq!(("compiled.lua", 2), (located.file.as_str(), located.line));
+
+        let err = act.exec("error('x', 0)").err().unwrap();
+        assert_eq!(1, ScriptError::find(&err).unwrap().line);
+        assert_eq!(None, ScriptError::find(&err).unwrap().source_line);
+        assert!(ScriptError::find(&act.call("missing.fn", ()).err().unwrap()).is_none());
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[test]
+fn test_actor_conversion_limits() {
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            cyclic = {name = "a", items = {}}
+            cyclic.items[1] = cyclic
+            deep = {{{1}}}
+            shared = {1}
+            twice = {a = shared, b = shared}
+        "#,
+        )
+        .unwrap();
+        let err = act.get_global("cyclic").err().unwrap().to_string();
+        assert!(err.contains("cycle of tables at .items[1]"), "{}", err);
+        // Tables seen twice aren't cycles.
+        assert_eq!(
+            "{a = {1}, b = {1}}",
+            act.get_global("twice").unwrap().to_string()
+        );
+
+        act.set_conversion_options(ConversionOptions {
+            max_depth: 2,
+            max_elements: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let err = act.get_global("deep").err().unwrap().to_string();
+        assert!(
+            err.contains("tables nested deeper than 2 at [1][1]"),
+            "{}",
+            err
+        );
+        let err = act.eval("return {1, 2, 3, 4}").err().unwrap();
+        assert!(err.to_string().contains("more than 3 table entries"));
+
+        act.set_conversion_options(ConversionOptions {
+            max_depth: 2,
+            lenient: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!("{{nil}}", act.get_global("deep").unwrap().to_string());
+        assert_eq!(
+            r#"{items = {nil}, name = "a"}"#,
+            act.get_global("cyclic").unwrap().to_string()
+        );
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_actor_call_typed() {
+    extern crate serde_derive;
+
+    #[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+    struct Item {
+        name: String,
+        price: f64,
+    }
+
+    fn test_actor(act: Actor) {
+        act.exec(
+            r#"
+            function total(items, discount)
+                local sum = 0
+                for _, item in ipairs(items) do sum = sum + item.price end
+                return sum - discount, #items
+            end
+            function cheapest(items)
+                table.sort(items, function(a, b) return a.price < b.price end)
+                return items[1]
+            end
+            function broken(items)
+                items[2].price = "free"
+                return items
+            end
+            function rename(names)
+                names[1], names[10] = names[10], names[1]
+                return names, type(next(names))
+            end
+        "#,
+        )
+        .unwrap();
+        let items = vec![
+            Item {
+                name: "a".to_string(),
+                price: 2.5,
+            },
+            Item {
+                name: "b".to_string(),
+                price: 1.0,
+            },
+        ];
+
+        let (sum, count): (f64, usize) = act.call_typed("total", (&items, 0.5)).unwrap();
+        assert_eq!((3.0, 2), (sum, count));
+        let cheapest: Item = act.call_typed("cheapest", (&items,)).unwrap();
+        assert_eq!(items[1], cheapest);
+
+        let err = act
+            .call_typed::<_, Vec<Item>>("broken", (&items,))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("field \"[2].price\": expected number, got string"));
+
+        // Integer keys stay integers in Lua, and back.
+        let names = HashMap::from([(1, "one".to_string()), (10, "ten".to_string())]);
+        let (renamed, key_type): (HashMap<i64, String>, String) =
+            act.call_typed("rename", (&names,)).unwrap();
+        assert_eq!("number", key_type);
+        assert_eq!(
+            HashMap::from([(1, "ten".to_string()), (10, "one".to_string())]),
+            renamed
+        );
+    }
+
+    test_actor(Actor::new_with_handler(None));
+    test_actor(Actor::new());
+}
@@ -0,0 +1,326 @@
+/*!
+An interactive Lua REPL attached to an `Actor`.
+
+Usage: `lua-actor-repl [-p|--package-path PATH]... [INIT_SCRIPT]...`
+
+Expressions are printed (e.g. `1 + 1`), statements are run (e.g. `x = 1`),
+and incomplete chunks (e.g. `function f()`) continue on the next lines.
+*/
+
+extern crate lua_actor;
+extern crate rlua;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use lua_actor::{actor::Actor, error::ScriptError, message::LuaMessage};
+use rlua::{Error, Value};
+
+const CHUNK_NAME: &str = "stdin";
+const HELP: &str = "\
+:globals      list the global variables defined since the start
+:reset        restart with a fresh actor (running the init scripts again)
+:load FILE    run a Lua file
+:help         show this help
+:quit         exit (as Ctrl-D)";
+
+struct Repl {
+    package_paths: Vec<String>,
+    init_scripts: Vec<String>,
+    actor: Actor,
+    builtins: HashSet<String>,
+}
+
+impl Repl {
+    fn new(package_paths: Vec<String>, init_scripts: Vec<String>) -> Repl {
+        let actor = Actor::new();
+        let builtins = global_names(&actor).into_iter().collect();
+        let repl = Repl {
+            package_paths,
+            init_scripts,
+            actor,
+            builtins,
+        };
+        repl.init();
+        repl
+    }
+
+    fn init(&self) {
+        for path in &self.package_paths {
+            let paths = format!("{};{}", package_path(path), self.package_path());
+            let result = self
+                .actor
+                .set_global("package.path", LuaMessage::from(paths));
+            if let Err(err) = result {
+                eprintln!("{}", err);
+            }
+        }
+        for script in &self.init_scripts {
+            self.load(script);
+        }
+    }
+    fn package_path(&self) -> String {
+        Option::from(
+            self.actor
+                .get_global("package.path")
+                .unwrap_or(LuaMessage::Nil),
+        )
+        .unwrap_or_default()
+    }
+
+    fn load(&self, file: &str) {
+        let result = fs::read_to_string(file)
+            .map_err(|err| Error::RuntimeError(format!("cannot read {}: {}", file, err)))
+            .and_then(|source| self.actor.exec_named(&source, file));
+        if let Err(err) = result {
+            print_error(&err);
+        }
+    }
+
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.splitn(2, char::is_whitespace);
+        match (
+            words.next().unwrap_or_default(),
+            words.next().map(str::trim),
+        ) {
+            (":globals", _) => {
+                let names = global_names(&self.actor);
+                for name in names.iter().filter(|name| !self.builtins.contains(*name)) {
+                    match self.actor.get_global(name) {
+                        Ok(value) => println!("{} = {}", name, pretty(&value, 0)),
+                        Err(_) => println!("{} = ?", name),
+                    }
+                }
+            }
+            (":reset", _) => {
+                self.actor.stop_handler();
+                *self = Repl::new(self.package_paths.clone(), self.init_scripts.clone());
+            }
+            (":load", Some(file)) if !file.is_empty() => self.load(file),
+            (":load", _) => eprintln!("usage: :load FILE"),
+            (":help", _) => println!("{}", HELP),
+            (":quit", _) | (":q", _) => return false,
+            (command, _) => eprintln!("unknown command {} (see :help)", command),
+        }
+        true
+    }
+
+    // Run the chunk, or return false when it's incomplete.
+    fn eval(&self, chunk: &str) -> bool {
+        match run_chunk(&self.actor, chunk) {
+            None => return false,
+            Some(Ok(LuaMessage::Nil)) => {}
+            Some(Ok(value)) => println!("{}", pretty(&value, 0)),
+            Some(Err(ref err)) => print_error(err),
+        }
+        true
+    }
+}
+
+// The result of the chunk, or None when it's incomplete (to be continued on the next line).
+fn run_chunk(actor: &Actor, chunk: &str) -> Option<Result<LuaMessage, Error>> {
+    // Expressions first, as the standalone lua.
+    let result = match actor.eval_named(&format!("return {}", chunk), CHUNK_NAME) {
+        Err(ref expression_err) => match syntax_error(expression_err) {
+            Some(incomplete_expression) => match actor.eval_named(chunk, CHUNK_NAME) {
+                Err(ref err) if syntax_error(err).is_some() => {
+                    if incomplete_expression || syntax_error(err) == Some(true) {
+                        return None;
+                    }
+                    Err(err.clone())
+                }
+                result => result,
+            },
+            None => Err(expression_err.clone()),
+        },
+        result => result,
+    };
+    Some(result)
+}
+
+// Whether the line is a command (`:help`), rather than a line of a chunk.
+fn is_command(chunk: &str, line: &str) -> bool {
+    chunk.is_empty() && line.trim_start().starts_with(':')
+}
+
+// Continue the chunk with the line, returning whether there's something to run.
+fn push_line(chunk: &mut String, line: &str) -> bool {
+    if !chunk.is_empty() {
+        chunk.push('\n');
+    }
+    chunk.push_str(line);
+    !chunk.trim().is_empty()
+}
+
+fn package_path(dir: &str) -> String {
+    if dir.contains('?') {
+        dir.to_string()
+    } else {
+        let dir = dir.trim_end_matches('/');
+        format!("{}/?.lua;{}/?/init.lua", dir, dir)
+    }
+}
+
+fn global_names(actor: &Actor) -> Vec<String> {
+    let lua = actor.lua();
+    let lua = lua.lock().unwrap();
+    let mut names = lua.context(|lua| {
+        lua.globals()
+            .pairs::<Value, Value>()
+            .filter_map(|pair| match pair {
+                Ok((Value::String(name), _)) => name.to_str().ok().map(str::to_string),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    });
+    names.sort();
+    names
+}
+
+// Some(incomplete_input) for syntax errors.
+fn syntax_error(err: &Error) -> Option<bool> {
+    let err = ScriptError::find(err).map_or(err, |err| &err.cause);
+    match err {
+        Error::SyntaxError {
+            incomplete_input, ..
+        } => Some(*incomplete_input),
+        _ => None,
+    }
+}
+
+fn print_error(err: &Error) {
+    match ScriptError::find(err) {
+        Some(err) => eprintln!("{}", err),
+        None => eprintln!("{}", err),
+    }
+}
+
+fn pretty(value: &LuaMessage, indent: usize) -> String {
+    let pad = "  ".repeat(indent + 1);
+    let close = "  ".repeat(indent);
+    match value {
+        LuaMessage::Table(table) if !table.is_empty() => {
+            let mut keys = table.keys().collect::<Vec<_>>();
+            keys.sort();
+            let fields = keys
+                .into_iter()
+                .map(|key| format!("{}{} = {},\n", pad, key, pretty(&table[key], indent + 1)))
+                .collect::<String>();
+            format!("{{\n{}{}}}", fields, close)
+        }
+        LuaMessage::Array(array) if !array.is_empty() => {
+            let items = array
+                .iter()
+                .map(|item| format!("{}{},\n", pad, pretty(item, indent + 1)))
+                .collect::<String>();
+            format!("{{\n{}{}}}", items, close)
+        }
+        LuaMessage::String(s) if indent > 0 => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+fn main() {
+    let mut package_paths = vec![];
+    let mut init_scripts = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--package-path" => match args.next() {
+                Some(path) => package_paths.push(path),
+                None => {
+                    eprintln!("{} needs a path", arg);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("usage: lua-actor-repl [-p|--package-path PATH]... [INIT_SCRIPT]...");
+                println!("{}", HELP);
+                return;
+            }
+            _ => init_scripts.push(arg),
+        }
+    }
+
+    let mut repl = Repl::new(package_paths, init_scripts);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut chunk = String::new();
+    loop {
+        print!("{}", if chunk.is_empty() { "> " } else { ">> " });
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+
+        if is_command(&chunk, &line) {
+            if !repl.command(line.trim()) {
+                break;
+            }
+            continue;
+        }
+        if !push_line(&mut chunk, &line) || repl.eval(&chunk) {
+            chunk.clear();
+        }
+    }
+    repl.actor.stop_handler();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        assert!(is_command("", "  :help"));
+        assert!(!is_command("t = {", ":x"));
+        assert!(!is_command("", "x = 1"));
+
+        let mut chunk = String::new();
+        assert!(!push_line(&mut chunk, "  "));
+        chunk.clear();
+        assert!(push_line(&mut chunk, "function f()"));
+        assert!(push_line(&mut chunk, ""));
+        assert!(push_line(&mut chunk, "end"));
+        assert_eq!("function f()\n\nend", chunk);
+    }
+
+    #[test]
+    fn chunks() {
+        let actor = Actor::new_with_handler(None);
+        let run = |chunk| run_chunk(&actor, chunk).map(|result| result.map_err(|e| e.to_string()));
+
+        assert_eq!(Some(Ok(LuaMessage::from(2))), run("1 + 1"));
+        assert_eq!(Some(Ok(LuaMessage::Nil)), run("x = 1"));
+        assert_eq!(Some(Ok(LuaMessage::from(1))), run("x"));
+
+        // Incomplete statements & expressions continue.
+        for chunk in &[
+            "function f()",
+            "if x then",
+            "t = {",
+            "x = 1 +",
+            "f(1,",
+            "--[[ note",
+        ] {
+            assert_eq!(None, run(chunk), "{}", chunk);
+        }
+        assert_eq!(
+            Some(Ok(LuaMessage::Nil)),
+            run("function f()\nreturn 3\nend")
+        );
+        assert_eq!(Some(Ok(LuaMessage::from(3))), run("f()"));
+
+        // Complete but invalid chunks fail.
+        for chunk in &["x = = 1", "end", "1 +* 2"] {
+            let err = run_chunk(&actor, chunk).unwrap().unwrap_err();
+            assert_eq!(Some(false), syntax_error(&err), "{}", chunk);
+        }
+        let err = run("error('boom')").unwrap().unwrap_err();
+        assert!(err.contains("boom"), "{}", err);
+    }
+}
@@ -0,0 +1,149 @@
+/*!
+Call a Lua function with JSON arguments, printing its result as JSON (feature `cli`).
+
+Usage: `lua-actor [-p|--package-path PATH]... -c|--call FUNCTION [-a|--args JSON] SCRIPT...`
+
+The arguments are a JSON array (or a single value, `-` to read it from stdin).
+A function returning many values prints them as a JSON array.
+
+Exit codes: 0 on success, 1 on Lua errors (reported as JSON on stderr), 2 on bad usage.
+*/
+
+extern crate lua_actor;
+extern crate rlua;
+#[macro_use]
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use lua_actor::{actor::Actor, error::ScriptError, message::LuaMessage};
+use rlua::Error;
+use serde_json::Value as Json;
+
+const USAGE: &str =
+    "usage: lua-actor [-p|--package-path PATH]... -c|--call FUNCTION [-a|--args JSON] SCRIPT...";
+
+struct Options {
+    package_paths: Vec<String>,
+    function: String,
+    args: Json,
+    scripts: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut package_paths = vec![];
+    let mut function = None;
+    let mut args = None;
+    let mut scripts = vec![];
+
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-p" | "--package-path" => package_paths.push(value()?),
+            "-c" | "--call" => function = Some(value()?),
+            "-a" | "--args" => args = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => scripts.push(arg),
+        }
+    }
+
+    let function = function.ok_or("missing the function to --call")?;
+    let args = match args.as_deref() {
+        Some("-") => {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .map_err(|err| format!("cannot read the arguments: {}", err))?;
+            input
+        }
+        Some(args) => args.to_string(),
+        None => "[]".to_string(),
+    };
+    let args = serde_json::from_str(&args).map_err(|err| format!("invalid --args: {}", err))?;
+    Ok(Options {
+        package_paths,
+        function,
+        args,
+        scripts,
+    })
+}
+
+fn fail(error: Json) -> ! {
+    eprintln!("{}", json!({ "error": error }));
+    process::exit(1);
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let actor = Actor::new_with_handler(None);
+    for path in &options.package_paths {
+        let paths =
+            Option::<String>::from(actor.get_global("package.path").unwrap()).unwrap_or_default();
+        let path = if path.contains('?') {
+            path.clone()
+        } else {
+            format!("{}/?.lua;{}/?/init.lua", path, path)
+        };
+        actor
+            .set_global(
+                "package.path",
+                LuaMessage::from(format!("{};{}", path, paths)),
+            )
+            .unwrap();
+    }
+
+    let run = || {
+        for script in &options.scripts {
+            let source = fs::read_to_string(script)
+                .map_err(|err| Error::RuntimeError(format!("cannot read {}: {}", script, err)))?;
+            actor.exec_named(&source, script)?;
+        }
+        let args = match options.args.clone() {
+            Json::Array(items) => items,
+            value => vec![value],
+        };
+        let args = args
+            .into_iter()
+            .map(|arg| serde_json::from_value(arg).map_err(Error::external))
+            .collect::<Result<Vec<LuaMessage>, _>>()?;
+        actor.call_multi(&options.function, args)
+    };
+    let results = match run() {
+        Ok(results) => Vec::from(results),
+        Err(err) => fail(match ScriptError::find(&err) {
+            Some(err) => json!({
+                "message": err.message,
+                "file": err.file,
+                "line": err.line,
+                "source_line": err.source_line,
+                "traceback": err.traceback,
+            }),
+            None => json!({ "message": err.to_string() }),
+        }),
+    };
+
+    // NaN & infinities become null.
+    let result = match results.len() {
+        1 => serde_json::to_value(results.into_iter().next().unwrap()),
+        _ => serde_json::to_value(LuaMessage::Array(results.into())),
+    };
+    match result {
+        Ok(result) => println!("{}", result),
+        Err(err) => fail(json!({ "message": err.to_string() })),
+    }
+}
@@ -0,0 +1,262 @@
+/*!
+Line coverage of the named chunks (see `Actor::load_named`) run by actors, written as LCOV.
+
+A `Coverage` is shared by the actors it's started on (see `Actor::start_coverage`),
+and `write_lcov` drains it into the counts of former runs.
+
+The lines of a function are known once it's called:
+the functions never called are missing, rather than reported as not hit.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use hook::{frame_info, HookEvent};
+use rlua::{Context, Error, Value};
+
+pub(crate) const HOOK_NAME: &str = "coverage";
+
+/// Hit counts by chunk name & line.
+#[derive(Clone, Default)]
+pub struct Coverage {
+    files: Arc<Mutex<HashMap<String, BTreeMap<u32, u64>>>>,
+}
+
+impl Coverage {
+    /// The hit counts of a chunk by line (including the lines not hit).
+    pub fn lines(&self, file: &str) -> Option<BTreeMap<u32, u64>> {
+        self.files.lock().unwrap().get(file).cloned()
+    }
+    /// The names of the chunks covered (sorted).
+    pub fn files(&self) -> Vec<String> {
+        let mut files = self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    /// Add the hit counts of another coverage.
+    pub fn merge(&self, other: &Coverage) {
+        if Arc::ptr_eq(&self.files, &other.files) {
+            return;
+        }
+        let other = other.files.lock().unwrap().clone();
+        self.add(other);
+    }
+    fn add(&self, other: HashMap<String, BTreeMap<u32, u64>>) {
+        let mut files = self.files.lock().unwrap();
+        for (file, lines) in other {
+            let counts = files.entry(file).or_default();
+            for (line, hits) in lines {
+                *counts.entry(line).or_insert(0) += hits;
+            }
+        }
+    }
+
+    /// The LCOV tracefile of the coverage.
+    pub fn lcov(&self) -> String {
+        let files = self.files.lock().unwrap();
+        let mut names = files.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut lcov = String::new();
+        for name in names {
+            let lines = &files[name];
+            lcov.push_str(&format!("TN:\nSF:{}\n", name));
+            for (line, hits) in lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            lcov.push_str(&format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                lines.len(),
+                lines.values().filter(|&&hits| hits > 0).count()
+            ));
+        }
+        lcov
+    }
+
+    /// Read the line counts (`SF` & `DA` records) of a LCOV tracefile.
+    pub fn from_lcov(lcov: &str) -> io::Result<Coverage> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid LCOV: {}", line),
+            )
+        };
+        let mut files = HashMap::<String, BTreeMap<u32, u64>>::new();
+        let mut file = None;
+        for line in lcov.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix("SF:") {
+                file = Some(name.to_string());
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let mut fields = data.split(',');
+                let (line_number, hits) = match (fields.next(), fields.next()) {
+                    (Some(line_number), Some(hits)) => (
+                        line_number.parse::<u32>().map_err(|_| invalid(line))?,
+                        hits.parse::<u64>().map_err(|_| invalid(line))?,
+                    ),
+                    _ => return Err(invalid(line)),
+                };
+                let name = file.clone().ok_or_else(|| invalid(line))?;
+                *files
+                    .entry(name)
+                    .or_default()
+                    .entry(line_number)
+                    .or_insert(0) += hits;
+            } else if line == "end_of_record" {
+                file = None;
+            }
+        }
+        Ok(Coverage {
+            files: Arc::new(Mutex::new(files)),
+        })
+    }
+
+    /// Write the LCOV tracefile, adding the counts already in the file.
+    ///
+    /// The counts written are drained (the lines are kept, not hit), so writing again
+    /// only adds the lines run since. They are kept if the file couldn't be written.
+    pub fn write_lcov(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let merged = match fs::read_to_string(path) {
+            Ok(lcov) => Coverage::from_lcov(&lcov)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Coverage::default(),
+            Err(err) => return Err(err),
+        };
+        let drained = self.drain();
+        merged.add(drained.clone());
+        fs::write(path, merged.lcov()).inspect_err(|_| self.add(drained))
+    }
+    // Take the hit counts, leaving the lines at 0.
+    fn drain(&self) -> HashMap<String, BTreeMap<u32, u64>> {
+        let mut files = self.files.lock().unwrap();
+        let drained = files.clone();
+        for hits in files.values_mut().flat_map(|lines| lines.values_mut()) {
+            *hits = 0;
+        }
+        drained
+    }
+
+    pub(crate) fn hook(&self) -> impl FnMut(Context, HookEvent) -> Result<(), Error> + Send {
+        let files = self.files.clone();
+        move |lua, event| {
+            let what = match event {
+                HookEvent::Call => "SL",
+                HookEvent::Line(_) => "S",
+                _ => return Ok(()),
+            };
+            let info = match frame_info(lua, 1, what)? {
+                Some(info) => info,
+                None => return Ok(()),
+            };
+            // Only the named chunks are files.
+            let file = match info.get::<_, String>("source")?.strip_prefix('@') {
+                Some(file) => file.to_string(),
+                None => return Ok(()),
+            };
+
+            let mut files = files.lock().unwrap();
+            let counts = files.entry(file).or_default();
+            match event {
+                HookEvent::Line(line) => *counts.entry(line).or_insert(0) += 1,
+                _ => {
+                    if let Value::Table(lines) = info.get::<_, Value>("activelines")? {
+                        for line in lines.pairs::<u32, Value>() {
+                            counts.entry(line?.0).or_insert(0);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actor::Actor;
+    use std::env;
+
+    #[test]
+    fn lcov() {
+        let coverage = Coverage::from_lcov(
+            "TN:\nSF:b.lua\nDA:1,2\nDA:2,0\nend_of_record\nTN:\nSF:a.lua\nDA:3,1\nend_of_record\n",
+        )
+        .unwrap();
+        coverage.merge(&Coverage::from_lcov("SF:b.lua\nDA:2,1\nDA:4,0\nend_of_record\n").unwrap());
+
+        assert_eq!(vec!["a.lua", "b.lua"], coverage.files());
+        assert_eq!(
+            "TN:\nSF:a.lua\nDA:3,1\nLF:1\nLH:1\nend_of_record\n\
+             TN:\nSF:b.lua\nDA:1,2\nDA:2,1\nDA:4,0\nLF:3\nLH:2\nend_of_record\n",
+            coverage.lcov()
+        );
+        assert!(Coverage::from_lcov("DA:1,1\n").is_err());
+        assert!(Coverage::from_lcov("SF:a.lua\nDA:x,1\n").is_err());
+    }
+
+    #[test]
+    fn line_hits() {
+        let script = "\
+local function sign(n)
+    if n < 0 then
+        return -1
+    end
+    return 1
+end
+local s = sign(1)
+s = s + sign(2)
+";
+        fn test_actor(act: Actor, coverage: &Coverage, script: &str) {
+            act.start_coverage(coverage).unwrap();
+            act.exec_named(script, "sign.lua").unwrap();
+            act.exec("local unnamed = 1").unwrap();
+            act.stop_coverage().unwrap();
+            // Nothing is counted once stopped.
+            act.exec_named(script, "sign.lua").unwrap();
+        }
+
+        let coverage = Coverage::default();
+        test_actor(Actor::new_with_handler(None), &coverage, script);
+        test_actor(Actor::new(), &coverage, script);
+
+        assert_eq!(vec!["sign.lua"], coverage.files());
+        let lines = coverage.lines("sign.lua").unwrap();
+        assert_eq!(Some(&4), lines.get(&2));
+        assert_eq!(Some(&0), lines.get(&3));
+        assert_eq!(Some(&4), lines.get(&5));
+        assert_eq!(Some(&2), lines.get(&7));
+        assert_eq!(Some(&2), lines.get(&8));
+
+        let path = env::temp_dir().join(format!("lua_actor_coverage_{}.info", std::process::id()));
+        let _ = fs::remove_file(&path);
+        coverage.write_lcov(&path).unwrap();
+        // Writing again doesn't count the same hits twice.
+        coverage.write_lcov(&path).unwrap();
+        assert_eq!(Some(&0), coverage.lines("sign.lua").unwrap().get(&2));
+        let written = Coverage::from_lcov(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(Some(&4), written.lines("sign.lua").unwrap().get(&2));
+        assert!(written.lcov().contains("DA:3,0\n"));
+
+        // The later runs are added.
+        test_actor(Actor::new(), &coverage, script);
+        coverage.write_lcov(&path).unwrap();
+        let written = Coverage::from_lcov(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(Some(&6), written.lines("sign.lua").unwrap().get(&2));
+        fs::remove_file(&path).unwrap();
+
+        // The counts are kept when the file can't be written.
+        test_actor(Actor::new(), &coverage, script);
+        let missing = env::temp_dir().join(format!("lua_actor_missing_{}", std::process::id()));
+        assert!(coverage.write_lcov(missing.join("lcov.info")).is_err());
+        assert_eq!(Some(&2), coverage.lines("sign.lua").unwrap().get(&2));
+    }
+}
@@ -0,0 +1,765 @@
+/*!
+A debugger serving the Debug Adapter Protocol over TCP (feature `debugger`).
+
+Editors attach to the address given to `Actor::debug`, then set breakpoints in named chunks
+(see `Actor::exec`), step in/over/out, and inspect the stack frames, locals & globals.
+The actor is paused (blocking its handler) while stopped at a breakpoint or a step.
+*/
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread;
+
+use hook::{debug_lib, frame_info, HookEvent};
+use message::{lua_type_name, LuaMessage};
+use rlua::{Context, Error, FromLua, Function, Table, Value};
+use serde_json::Value as Json;
+
+pub(crate) const HOOK_NAME: &str = "debugger";
+const HANDLES_KEY: &str = "lua_actor.debugger_handles";
+const THREAD_ID: u32 = 1;
+// The requests of editors are small; bigger ones are refused before allocating them.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Run,
+    Pause,
+    In,
+    Over(u32),
+    Out(u32),
+}
+
+// The expandable variables of a pause: the locals of a frame, or a table kept in the registry.
+#[derive(Debug, Clone, Copy)]
+enum Handle {
+    Locals(u32),
+    Table,
+}
+
+struct Session {
+    client: Option<TcpStream>,
+    seq: u64,
+    breakpoints: HashMap<String, HashSet<u32>>,
+    step: Step,
+    paused: bool,
+    depth: u32,
+    // Inspection requests answered by the paused hook (which owns the VM).
+    inspections: VecDeque<Json>,
+    handles: Vec<Handle>,
+}
+
+impl Session {
+    fn send(&mut self, mut message: Json) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        if let Some(ref mut client) = self.client {
+            let body = message.to_string();
+            let _ = write!(client, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+                .and_then(|_| client.flush());
+        }
+    }
+    fn resume(&mut self, step: Step) {
+        self.step = step;
+        self.paused = false;
+    }
+    fn detach(&mut self) {
+        self.client = None;
+        self.breakpoints.clear();
+        self.inspections.clear();
+        self.resume(Step::Run);
+    }
+    fn has_breakpoint(&self, source: &str, line: u32) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(path, lines)| lines.contains(&line) && same_source(path, source))
+    }
+}
+
+type Shared = Arc<(Mutex<Session>, Condvar)>;
+
+/// A debugger attached to an `Actor`, stopped by `Actor::stop_debugger`.
+pub struct Debugger {
+    shared: Shared,
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Debugger {
+    pub(crate) fn listen(addr: impl ToSocketAddrs) -> Result<Debugger, Error> {
+        let listener = TcpListener::bind(addr).map_err(Error::external)?;
+        let local_addr = listener.local_addr().map_err(Error::external)?;
+        let shared: Shared = Arc::new((
+            Mutex::new(Session {
+                client: None,
+                seq: 0,
+                breakpoints: HashMap::new(),
+                step: Step::Run,
+                paused: false,
+                depth: 0,
+                inspections: VecDeque::new(),
+                handles: vec![],
+            }),
+            Condvar::new(),
+        ));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let (server_shared, server_stopped) = (shared.clone(), stopped.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if server_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let shared = server_shared.clone();
+                    thread::spawn(move || serve(&shared, stream));
+                }
+            }
+        });
+
+        Ok(Debugger {
+            shared,
+            local_addr,
+            stopped,
+        })
+    }
+
+    /// The address editors attach to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Called by the hook of the actor VM on each event.
+    pub(crate) fn hook(&self) -> impl FnMut(Context, HookEvent) -> Result<(), Error> + Send {
+        let shared = self.shared.clone();
+        move |lua, event| on_hook(&shared, lua, event)
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let (mutex, cvar) = &*self.shared;
+        {
+            let mut session = mutex.lock().unwrap();
+            if let Some(ref client) = session.client {
+                let _ = client.shutdown(Shutdown::Both);
+            }
+            session.detach();
+        }
+        cvar.notify_all();
+        // Wake up the listener to let it see it's stopped.
+        let _ = TcpStream::connect(self.local_addr);
+    }
+}
+
+fn serve(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    {
+        let mut session = shared.0.lock().unwrap();
+        // One client at a time.
+        if session.client.is_some() {
+            return Ok(());
+        }
+        session.client = Some(stream.try_clone()?);
+    }
+
+    let mut reader = BufReader::new(stream);
+    let result = (|| {
+        while let Some(request) = read_message(&mut reader)? {
+            if !on_request(shared, &request) {
+                break;
+            }
+        }
+        Ok(())
+    })();
+
+    let (mutex, cvar) = &**shared;
+    mutex.lock().unwrap().detach();
+    cvar.notify_all();
+    result
+}
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "message of {} bytes exceeds the limit of {} bytes",
+                length, MAX_MESSAGE_LENGTH
+            ),
+        ));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(io::Error::from)
+}
+
+fn response(request: &Json, body: Json) -> Json {
+    json!({
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+        "success": true,
+        "body": body,
+    })
+}
+
+fn error_response(request: &Json, message: &str) -> Json {
+    json!({
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+        "success": false,
+        "message": message,
+    })
+}
+
+fn event_message(name: &str, body: Json) -> Json {
+    json!({ "type": "event", "event": name, "body": body })
+}
+
+// Handle a request of the client, returning false once it disconnects.
+fn on_request(shared: &Shared, request: &Json) -> bool {
+    let (mutex, cvar) = &**shared;
+    let mut session = mutex.lock().unwrap();
+    let args = &request["arguments"];
+    let body = match request["command"].as_str().unwrap_or_default() {
+        "initialize" => {
+            session.send(response(
+                request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }),
+            ));
+            session.send(event_message("initialized", json!({})));
+            return true;
+        }
+        "attach" | "launch" | "configurationDone" | "setExceptionBreakpoints" => json!({}),
+        "setBreakpoints" => {
+            let source = &args["source"];
+            let path = source["path"]
+                .as_str()
+                .or_else(|| source["name"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            let lines = args["breakpoints"]
+                .as_array()
+                .map(|breakpoints| {
+                    breakpoints
+                        .iter()
+                        .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                        .map(|line| line as u32)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            let body = json!({
+                "breakpoints": lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect::<Vec<_>>(),
+            });
+            session
+                .breakpoints
+                .insert(path, lines.into_iter().collect());
+            body
+        }
+        "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "actor" }] }),
+        "stackTrace" | "scopes" | "variables" | "evaluate" => {
+            if session.paused {
+                session.inspections.push_back(request.clone());
+                cvar.notify_all();
+            } else {
+                session.send(error_response(request, "the actor is running"));
+            }
+            return true;
+        }
+        "continue" => {
+            session.resume(Step::Run);
+            json!({ "allThreadsContinued": true })
+        }
+        "next" => {
+            let depth = session.depth;
+            session.resume(Step::Over(depth));
+            json!({})
+        }
+        "stepIn" => {
+            session.resume(Step::In);
+            json!({})
+        }
+        "stepOut" => {
+            let depth = session.depth;
+            session.resume(Step::Out(depth));
+            json!({})
+        }
+        "pause" => {
+            if !session.paused {
+                session.step = Step::Pause;
+            }
+            json!({})
+        }
+        "disconnect" => {
+            session.send(response(request, json!({})));
+            return false;
+        }
+        command => {
+            session.send(error_response(
+                request,
+                &format!("unsupported request '{}'", command),
+            ));
+            return true;
+        }
+    };
+    session.send(response(request, body));
+    cvar.notify_all();
+    true
+}
+
+fn on_hook(shared: &Shared, lua: Context, event: HookEvent) -> Result<(), Error> {
+    let line = match event {
+        HookEvent::Line(line) => line,
+        _ => return Ok(()),
+    };
+    let (mutex, cvar) = &**shared;
+    let mut session = mutex.lock().unwrap();
+    if session.client.is_none() {
+        return Ok(());
+    }
+
+    let reason = match session.step {
+        Step::Pause => Some("pause"),
+        Step::In => Some("step"),
+        Step::Over(depth) if stack_depth(lua)? <= depth => Some("step"),
+        Step::Out(depth) if stack_depth(lua)? < depth => Some("step"),
+        _ if session.breakpoints.is_empty() => None,
+        _ => match chunk_name(&frame_info(lua, 1, "S")?) {
+            Some(ref source) if session.has_breakpoint(source, line) => Some("breakpoint"),
+            _ => None,
+        },
+    };
+    let reason = match reason {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+
+    session.step = Step::Run;
+    session.paused = true;
+    session.depth = stack_depth(lua)?;
+    session.handles.clear();
+    lua.set_named_registry_value(HANDLES_KEY, lua.create_table()?)?;
+    session.send(event_message(
+        "stopped",
+        json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+    ));
+    loop {
+        while let Some(request) = session.inspections.pop_front() {
+            let message = match inspect(lua, &mut session.handles, &request) {
+                Ok(body) => response(&request, body),
+                Err(err) => error_response(&request, &err.to_string()),
+            };
+            session.send(message);
+        }
+        if !session.paused {
+            break;
+        }
+        session = cvar.wait(session).unwrap();
+    }
+    Ok(())
+}
+
+fn stack_depth(lua: Context) -> Result<u32, Error> {
+    let mut depth = 0;
+    while frame_info(lua, depth + 1, "l")?.is_some() {
+        depth += 1;
+    }
+    Ok(depth)
+}
+
+fn chunk_name(info: &Option<Table>) -> Option<String> {
+    let source = info.as_ref()?.get::<_, String>("source").ok()?;
+    source.strip_prefix('@').map(str::to_string)
+}
+
+// Paths of editors are often absolute while chunk names are relative.
+fn same_source(path: &str, chunk_name: &str) -> bool {
+    let (path, chunk_name) = (path.replace('\\', "/"), chunk_name.replace('\\', "/"));
+    path == chunk_name
+        || path.ends_with(&format!("/{}", chunk_name.trim_start_matches("./")))
+        || chunk_name.ends_with(&format!("/{}", path))
+}
+
+fn inspect(lua: Context, handles: &mut Vec<Handle>, request: &Json) -> Result<Json, Error> {
+    let args = &request["arguments"];
+    match request["command"].as_str().unwrap_or_default() {
+        "stackTrace" => {
+            let mut frames = vec![];
+            let mut level = 1;
+            while let Some(info) = frame_info(lua, level, "nSl")? {
+                let what = info.get::<_, String>("what")?;
+                if what != "C" {
+                    let name = match info.get::<_, Option<String>>("name")? {
+                        Some(name) => name,
+                        None if what == "main" => "main chunk".to_string(),
+                        None => "?".to_string(),
+                    };
+                    let source = match chunk_name(&Some(info.clone())) {
+                        Some(path) => json!({ "name": path, "path": path }),
+                        None => json!({ "name": info.get::<_, String>("short_src")? }),
+                    };
+                    frames.push(json!({
+                        "id": level,
+                        "name": name,
+                        "source": source,
+                        "line": info.get::<_, i64>("currentline")?,
+                        "column": 1,
+                    }));
+                }
+                level += 1;
+            }
+            Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+        }
+        "scopes" => {
+            let level = args["frameId"].as_u64().unwrap_or(1) as u32;
+            let locals = push_handle(handles, Handle::Locals(level));
+            let globals = push_table(lua, handles, lua.globals())?;
+            Ok(json!({
+                "scopes": [
+                    { "name": "Locals", "variablesReference": locals, "expensive": false },
+                    { "name": "Globals", "variablesReference": globals, "expensive": true },
+                ],
+            }))
+        }
+        "variables" => {
+            let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+            let variables = match handles.get(reference.wrapping_sub(1)) {
+                Some(&Handle::Locals(level)) => locals(lua, level)?,
+                Some(&Handle::Table) => {
+                    let table = lua
+                        .named_registry_value::<_, Table>(HANDLES_KEY)?
+                        .get::<_, Table>(reference)?;
+                    fields(table)?
+                }
+                None => vec![],
+            };
+            let variables = variables
+                .into_iter()
+                .map(|(name, value)| {
+                    let (text, kind, reference) = render(lua, handles, value)?;
+                    Ok(json!({
+                        "name": name,
+                        "value": text,
+                        "type": kind,
+                        "variablesReference": reference,
+                    }))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(json!({ "variables": variables }))
+        }
+        "evaluate" => {
+            let expression = args["expression"].as_str().unwrap_or_default();
+            let local = match args["frameId"].as_u64() {
+                Some(level) => locals(lua, level as u32)?
+                    .into_iter()
+                    .find(|(name, _)| name == expression)
+                    .map(|(_, value)| value),
+                None => None,
+            };
+            let value = match local {
+                Some(value) => value,
+                None => lua
+                    .load(&format!("return {}", expression))
+                    .set_name("=evaluate")?
+                    .eval::<Value>()?,
+            };
+            let (text, kind, reference) = render(lua, handles, value)?;
+            Ok(json!({ "result": text, "type": kind, "variablesReference": reference }))
+        }
+        command => Err(Error::RuntimeError(format!(
+            "unsupported request '{}'",
+            command
+        ))),
+    }
+}
+
+fn push_handle(handles: &mut Vec<Handle>, handle: Handle) -> usize {
+    handles.push(handle);
+    handles.len()
+}
+
+fn push_table<'lua>(
+    lua: Context<'lua>,
+    handles: &mut Vec<Handle>,
+    table: Table<'lua>,
+) -> Result<usize, Error> {
+    let reference = push_handle(handles, Handle::Table);
+    lua.named_registry_value::<_, Table>(HANDLES_KEY)?
+        .set(reference, table)?;
+    Ok(reference)
+}
+
+fn locals(lua: Context, level: u32) -> Result<Vec<(String, Value)>, Error> {
+    let getlocal = debug_lib(lua)?.get::<_, Function>("getlocal")?;
+    let mut locals = vec![];
+    for index in 1.. {
+        // Level 1 of getlocal is the hook itself.
+        match getlocal.call::<_, (Option<String>, Value)>((level + 1, index))? {
+            (Some(name), value) => {
+                // Skip the internal ones, e.g. "(for state)".
+                if !name.starts_with('(') {
+                    locals.push((name, value));
+                }
+            }
+            (None, _) => break,
+        }
+    }
+    Ok(locals)
+}
+
+fn fields(table: Table) -> Result<Vec<(String, Value)>, Error> {
+    let mut fields = table
+        .pairs::<Value, Value>()
+        .map(|pair| {
+            let (key, value) = pair?;
+            let key = match key {
+                Value::String(s) => (1, 0, s.to_str()?.to_string()),
+                Value::Integer(i) => (0, i, format!("[{}]", i)),
+                key => (2, 0, format!("[{}]", lua_type_name(&key))),
+            };
+            Ok((key, value))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(fields
+        .into_iter()
+        .map(|((_, _, name), value)| (name, value))
+        .collect())
+}
+
+// Render a value by `LuaMessage`, while tables are expanded lazily (they could be cyclic).
+fn render<'lua>(
+    lua: Context<'lua>,
+    handles: &mut Vec<Handle>,
+    value: Value<'lua>,
+) -> Result<(String, &'static str, usize), Error> {
+    let kind = lua_type_name(&value);
+    match value {
+        Value::Table(table) => {
+            let len = table.clone().pairs::<Value, Value>().count();
+            let reference = push_table(lua, handles, table)?;
+            Ok((format!("table ({} entries)", len), kind, reference))
+        }
+        Value::String(_) => {
+            let text = match LuaMessage::from_lua(value, lua)? {
+                LuaMessage::String(s) => format!("{:?}", s),
+                message => message.to_string(),
+            };
+            Ok((text, kind, 0))
+        }
+        Value::Thread(_) | Value::LightUserData(_) | Value::Error(_) => {
+            Ok((kind.to_string(), kind, 0))
+        }
+        value => {
+            let message = LuaMessage::from_lua(value, lua)?;
+            let text = message.to_string();
+            // Functions & userdata are kept in the registry by the conversion.
+            match message {
+                LuaMessage::Function(func) => func.release(lua)?,
+                LuaMessage::Handle(handle) => handle.release(lua)?,
+                _ => {}
+            }
+            Ok((text, kind, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actor::Actor;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        seq: u64,
+    }
+
+    impl Client {
+        fn request(&mut self, command: &str, arguments: Json) -> Json {
+            self.seq += 1;
+            let body = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            write!(
+                self.writer,
+                "Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["type"] == "response" && message["request_seq"] == self.seq {
+                    assert_eq!(json!(true), message["success"], "{}", message);
+                    return message["body"].clone();
+                }
+            }
+        }
+        fn stopped(&mut self) -> Json {
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["event"] == "stopped" {
+                    return message["body"].clone();
+                }
+            }
+        }
+        fn top_frame(&mut self) -> Json {
+            self.request("stackTrace", json!({ "threadId": THREAD_ID }))["stackFrames"][0].clone()
+        }
+    }
+
+    #[test]
+    fn same_sources() {
+        assert!(same_source("scripts/a.lua", "scripts/a.lua"));
+        assert!(same_source(
+            "/home/me/project/scripts/a.lua",
+            "scripts/a.lua"
+        ));
+        assert!(same_source("C:\\project\\a.lua", "a.lua"));
+        assert!(!same_source("/home/me/project/scripts/a.lua", "b.lua"));
+        assert!(!same_source("/home/me/project/xa.lua", "a.lua"));
+    }
+
+    #[test]
+    fn message_length_limit() {
+        let body = r#"{"seq":1}"#;
+        let mut reader = io::Cursor::new(format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        assert_eq!(
+            json!({ "seq": 1 }),
+            read_message(&mut reader).unwrap().unwrap()
+        );
+
+        let mut reader = io::Cursor::new("Content-Length: 1000000000000\r\n\r\n");
+        let err = read_message(&mut reader).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn debug_session() {
+        fn test_actor(act: Actor) {
+            let debugger = act.debug("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(debugger.local_addr()).unwrap();
+            let mut client = Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+                seq: 0,
+            };
+            client.request("initialize", json!({ "adapterID": "lua_actor" }));
+            client.request("attach", json!({}));
+            let breakpoints = client.request(
+                "setBreakpoints",
+                json!({
+                    "source": { "path": "/project/scripts/debug.lua" },
+                    "breakpoints": [{ "line": 3 }],
+                }),
+            );
+            assert_eq!(json!(true), breakpoints["breakpoints"][0]["verified"]);
+            client.request("configurationDone", json!({}));
+
+            let runner = act.clone();
+            let script = thread::spawn(move || {
+                runner.exec_named(
+                    r#"
+                    local function double(n)
+                        local result = n * 2
+                        return result
+                    end
+                    config = { name = "demo", list = { 1, 2 } }
+                    local x = 41
+                    local y = double(x)
+                    total = y + 1
+                "#,
+                    "scripts/debug.lua",
+                )
+            });
+
+            assert_eq!(json!("breakpoint"), client.stopped()["reason"]);
+            let frame = client.top_frame();
+            assert_eq!(json!(3), frame["line"]);
+            assert_eq!(json!("double"), frame["name"]);
+            assert_eq!(json!("scripts/debug.lua"), frame["source"]["path"]);
+
+            let scopes = client.request("scopes", json!({ "frameId": frame["id"] }));
+            let locals = client.request(
+                "variables",
+                json!({ "variablesReference": scopes["scopes"][0]["variablesReference"] }),
+            );
+            assert_eq!(json!("n"), locals["variables"][0]["name"]);
+            assert_eq!(json!("41"), locals["variables"][0]["value"]);
+            let globals = client.request(
+                "variables",
+                json!({ "variablesReference": scopes["scopes"][1]["variablesReference"] }),
+            );
+            let config = globals["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["name"] == "config")
+                .unwrap()
+                .clone();
+            let fields = client.request(
+                "variables",
+                json!({ "variablesReference": config["variablesReference"] }),
+            );
+            assert_eq!(json!("\"demo\""), fields["variables"][1]["value"]);
+
+            let evaluated = client.request(
+                "evaluate",
+                json!({ "expression": "n", "frameId": frame["id"] }),
+            );
+            assert_eq!(json!("41"), evaluated["result"]);
+
+            client.request("next", json!({ "threadId": THREAD_ID }));
+            assert_eq!(json!("step"), client.stopped()["reason"]);
+            assert_eq!(json!(4), client.top_frame()["line"]);
+
+            // The rest of line 8 runs without a new line event.
+            client.request("stepOut", json!({ "threadId": THREAD_ID }));
+            assert_eq!(json!("step"), client.stopped()["reason"]);
+            assert_eq!(json!(9), client.top_frame()["line"]);
+
+            client.request("continue", json!({ "threadId": THREAD_ID }));
+            script.join().unwrap().unwrap();
+            client.request("disconnect", json!({}));
+            assert_eq!(Some(83), Option::from(act.get_global("total").unwrap()));
+            act.stop_debugger(debugger).unwrap();
+        }
+
+        test_actor(Actor::new_with_handler(None));
+        test_actor(Actor::new());
+    }
+}
@@ -0,0 +1,171 @@
+/*!
+Script errors located in their chunk (file), line & source line.
+
+Chunks loaded with a name (e.g. `Actor::exec(source, Some("scripts/init.lua"))`)
+report that name in their errors, and their sources are kept to show the offending line.
+*/
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+
+use rlua::{Context, Error, Table, Value};
+
+const SOURCES_KEY: &str = "lua_actor.sources";
+const TRACEBACK_HEADER: &str = "stack traceback:";
+
+/// An error raised by a script, with the location it was raised at.
+///
+/// It's returned as `Error::ExternalError`; get it back with `ScriptError::find`.
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    /// The chunk name (e.g. the file path) of the script.
+    pub file: String,
+    pub line: u32,
+    /// The error message, without its location.
+    pub message: String,
+    /// The Lua stack traceback, if any.
+    pub traceback: Option<String>,
+    /// The offending line of the source, for named chunks.
+    pub source_line: Option<String>,
+    /// The original error.
+    pub cause: Error,
+}
+
+impl ScriptError {
+    /// Find the `ScriptError` of an error returned by an `Actor`, if any.
+    pub fn find(error: &Error) -> Option<&ScriptError> {
+        match error {
+            Error::ExternalError(err) => err.downcast_ref::<ScriptError>(),
+            Error::CallbackError { cause, .. } => Self::find(cause),
+            _ => None,
+        }
+    }
+
+    /// Turn the error into a `ScriptError` when it tells where it was raised.
+    pub fn locate(lua: Context, error: Error) -> Error {
+        let (text, traceback) = match &error {
+            Error::SyntaxError { message, .. } => (message.clone(), None),
+            Error::RuntimeError(message) => match message.find(TRACEBACK_HEADER) {
+                Some(i) => (
+                    message[..i].trim_end().to_string(),
+                    Some(message[i..].to_string()),
+                ),
+                None => (message.clone(), None),
+            },
+            Error::CallbackError { traceback, cause } => {
+                (cause.to_string(), Some(traceback.clone()))
+            }
+            _ => return error,
+        };
+
+        let located = match split_location(&text) {
+            Some((file, line, message)) => Some((file.to_string(), line, message.to_string())),
+            None => traceback
+                .as_ref()
+                .and_then(|traceback| {
+                    traceback
+                        .lines()
+                        .filter_map(|frame| split_location(frame.trim()))
+                        .next()
+                })
+                .map(|(file, line, _)| (file.to_string(), line, text.clone())),
+        };
+        match located {
+            Some((file, line, message)) => Error::ExternalError(Arc::new(ScriptError {
+                source_line: source_line(lua, &file, line),
+                file,
+                line,
+                message,
+                traceback,
+                cause: error,
+            })),
+            None => error,
+        }
+    }
+
+    /// Keep the source of a named chunk, to show the offending line of its errors.
+    pub(crate) fn remember_source(lua: Context, name: &str, source: &str) -> Result<(), Error> {
+        let sources = match lua.named_registry_value::<_, Value>(SOURCES_KEY)? {
+            Value::Table(t) => t,
+            _ => {
+                let t = lua.create_table()?;
+                lua.set_named_registry_value(SOURCES_KEY, t.clone())?;
+                t
+            }
+        };
+        sources.set(name, source)
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)?;
+        if let Some(ref source_line) = self.source_line {
+            write!(f, "\n    {} | {}", self.line, source_line)?;
+        }
+        if let Some(ref traceback) = self.traceback {
+            write!(f, "\n{}", traceback)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ScriptError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+// Split "file:line: message" (also the "file:line: in ..." frames of tracebacks).
+fn split_location(text: &str) -> Option<(&str, u32, &str)> {
+    // Unnamed chunks are `[string "first line of the source"]`, which could contain ':'.
+    let mut from = if text.starts_with("[string \"") {
+        text.find("\"]").map_or(0, |i| i + 2)
+    } else {
+        0
+    };
+    while let Some(i) = text[from..].find(':').map(|i| from + i) {
+        let rest = &text[i + 1..];
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits > 0 && i > 0 && rest[digits..].starts_with(": ") {
+            if let Ok(line) = rest[..digits].parse() {
+                return Some((&text[..i], line, &rest[digits + 2..]));
+            }
+        }
+        from = i + 1;
+    }
+    None
+}
+
+fn source_line(lua: Context, file: &str, line: u32) -> Option<String> {
+    let sources = lua.named_registry_value::<_, Table>(SOURCES_KEY).ok()?;
+    let source = sources.get::<_, Option<String>>(file).ok()??;
+    source
+        .lines()
+        .nth((line as usize).checked_sub(1)?)
+        .map(|line| line.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        assert_eq!(
+            Some(("scripts/init.lua", 12, "boom")),
+            split_location("scripts/init.lua:12: boom")
+        );
+        assert_eq!(
+            Some(("C:\\init.lua", 3, "in main chunk")),
+            split_location("C:\\init.lua:3: in main chunk")
+        );
+        assert_eq!(
+            Some(("[string \"x = 1:2: y\"]", 1, "bad")),
+            split_location("[string \"x = 1:2: y\"]:1: bad")
+        );
+        assert_eq!(None, split_location("no location: here"));
+        assert_eq!(None, split_location("[C]: in ?"));
+    }
+}
@@ -0,0 +1,170 @@
+/*!
+Topic based events between Lua scripts & Rust subscribers.
+
+Topics are dot separated segments (e.g. `order.created`),
+and patterns could use `*` for exactly one segment & `**` for any remaining segments.
+*/
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use fp_rust::{common::SubscriptionFunc, publisher::Publisher};
+use message::LuaMessage;
+use rlua::{Context, Error, Function, Nil, Table, Value};
+
+const LUA_HANDLERS_KEY: &str = "lua_actor.event_handlers";
+
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The `(topic, payload)` pair delivered to subscribers.
+pub type LuaEvent = (String, LuaMessage);
+
+pub type EventSubscription = Arc<Mutex<SubscriptionFunc<LuaEvent>>>;
+
+/// Check whether a topic matches a subscription pattern.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    fn matches(pattern: &[&str], topic: &[&str]) -> bool {
+        match (pattern.first(), topic.first()) {
+            (Some(&"**"), _) => {
+                (0..=topic.len()).any(|skip| matches(&pattern[1..], &topic[skip..]))
+            }
+            (Some(p), Some(t)) => (*p == "*" || p == t) && matches(&pattern[1..], &topic[1..]),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    matches(
+        &pattern.split('.').collect::<Vec<_>>(),
+        &topic.split('.').collect::<Vec<_>>(),
+    )
+}
+
+/// The event bus of an `Actor`, shared by its Lua functions & Rust subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    publisher: Arc<Mutex<Publisher<LuaEvent>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            publisher: Arc::new(Mutex::new(Publisher::new())),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(
+        &self,
+        pattern: &str,
+        mut func: impl FnMut(&str, LuaMessage) + Send + Sync + 'static,
+    ) -> EventSubscription {
+        let pattern = pattern.to_string();
+        self.publisher
+            .lock()
+            .unwrap()
+            .subscribe_fn(move |event: Arc<LuaEvent>| {
+                if topic_matches(&pattern, &event.0) {
+                    func(&event.0, event.1.clone());
+                }
+            })
+    }
+    pub fn unsubscribe(&self, subscription: EventSubscription) {
+        self.publisher.lock().unwrap().unsubscribe(subscription);
+    }
+
+    /// Deliver an event to the Rust subscribers, then to the Lua handlers of the VM.
+    ///
+    /// A failing Lua handler doesn't stop the others: their errors are returned together after.
+    pub fn dispatch(&self, lua: Context, topic: &str, payload: LuaMessage) -> Result<(), Error> {
+        // Subscribers could (un)subscribe while being notified.
+        let mut publisher = self.publisher.lock().unwrap().clone();
+        publisher.publish((topic.to_string(), payload.clone()));
+
+        let handlers = match lua.named_registry_value::<_, Value>(LUA_HANDLERS_KEY)? {
+            Value::Table(t) => t,
+            _ => return Ok(()),
+        };
+        let mut matched = Vec::<(usize, Function)>::new();
+        for pair in handlers.pairs::<usize, Table>() {
+            let (id, handler) = pair?;
+            if topic_matches(&handler.get::<_, String>("pattern")?, topic) {
+                matched.push((id, handler.get("func")?));
+            }
+        }
+        // `pairs` has no order, but the ids are increasing: run in registration order.
+        matched.sort_by_key(|(id, _)| *id);
+        let failures = matched
+            .into_iter()
+            .filter_map(|(id, func)| {
+                func.call::<_, ()>((payload.clone(), topic))
+                    .err()
+                    .map(|err| format!("handler {}: {}", id, err))
+            })
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::RuntimeError(format!(
+                "the event handlers of '{}' failed ({})",
+                topic,
+                failures.join("; ")
+            )))
+        }
+    }
+
+    /// Define `emit(topic, payload)`, `on(pattern, func) -> id` & `off(id)` as Lua globals.
+    pub fn def_lua_fns(&self, lua: Context) -> Result<(), Error> {
+        let bus = self.clone();
+        let emit = lua.create_function(move |lua, (topic, payload): (String, LuaMessage)| {
+            bus.dispatch(lua, &topic, payload)
+        })?;
+        let on = lua.create_function(|lua, (pattern, func): (String, Function)| {
+            let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::SeqCst);
+            let handler = lua.create_table()?;
+            handler.set("pattern", pattern)?;
+            handler.set("func", func)?;
+            Self::lua_handlers(lua)?.set(id, handler)?;
+            Ok(id)
+        })?;
+        let off = lua.create_function(|lua, id: usize| Self::lua_handlers(lua)?.set(id, Nil))?;
+
+        let globals = lua.globals();
+        globals.set("emit", emit)?;
+        globals.set("on", on)?;
+        globals.set("off", off)
+    }
+    fn lua_handlers(lua: Context) -> Result<Table, Error> {
+        match lua.named_registry_value::<_, Value>(LUA_HANDLERS_KEY)? {
+            Value::Table(t) => Ok(t),
+            _ => {
+                let t = lua.create_table()?;
+                lua.set_named_registry_value(LUA_HANDLERS_KEY, t.clone())?;
+                Ok(t)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_patterns() {
+        assert!(topic_matches("order.created", "order.created"));
+        assert!(!topic_matches("order.created", "order.deleted"));
+        assert!(topic_matches("order.*", "order.created"));
+        assert!(!topic_matches("order.*", "order"));
+        assert!(!topic_matches("order.*", "order.item.added"));
+        assert!(topic_matches("*.created", "user.created"));
+        assert!(topic_matches("order.**", "order"));
+        assert!(topic_matches("order.**", "order.item.added"));
+        assert!(topic_matches("**", "anything.at.all"));
+        assert!(topic_matches("**.added", "order.item.added"));
+        assert!(!topic_matches("**.added", "order.item.removed"));
+    }
+}
@@ -0,0 +1,180 @@
+/*!
+One Lua debug hook per VM, shared by the debugger, the profiler & the coverage.
+
+The debug library is loaded by `load_debug_lib` into the registry of the VM,
+so the hooks can use it while Lua scripts can't.
+*/
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rlua::{Context, Error, Function, Lua, Nil, StdLib, Table, Value};
+
+const DEBUG_LIB_KEY: &str = "lua_actor.debug";
+
+/// Load the debug library into the VM (once), hidden in the registry for the hooks.
+///
+/// Actors call it only when a hook is installed, so VMs without hooks never have it.
+pub fn load_debug_lib(lua: &Lua) -> Result<(), Error> {
+    if lua.context(|lua| debug_lib(lua).is_ok()) {
+        return Ok(());
+    }
+    // The debug library is unsafe only when scripts could reach it:
+    // it's moved out of their reach before returning.
+    unsafe { lua.unsafe_load_from_std_lib(StdLib::DEBUG)? };
+    lua.context(|lua| {
+        let globals = lua.globals();
+        lua.set_named_registry_value(DEBUG_LIB_KEY, globals.get::<_, Table>("debug")?)?;
+        globals.set("debug", Nil)?;
+        match globals.get::<_, Option<Table>>("package")? {
+            Some(package) => package.get::<_, Table>("loaded")?.set("debug", Nil),
+            None => Ok(()),
+        }
+    })
+}
+
+/// The hidden debug library of the VM.
+pub(crate) fn debug_lib(lua: Context) -> Result<Table, Error> {
+    match lua.named_registry_value::<_, Value>(DEBUG_LIB_KEY)? {
+        Value::Table(t) => Ok(t),
+        _ => Err(Error::RuntimeError(
+            "the debug library is unavailable (see lua_actor::hook::load_debug_lib)".to_string(),
+        )),
+    }
+}
+
+/// The function running at the level of the stack (1 being the hooked function).
+pub(crate) fn frame_info<'lua>(
+    lua: Context<'lua>,
+    level: u32,
+    what: &str,
+) -> Result<Option<Table<'lua>>, Error> {
+    // Level 1 of getinfo is the hook itself.
+    debug_lib(lua)?
+        .get::<_, Function>("getinfo")?
+        .call((level + 1, what))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HookEvent {
+    Call,
+    Return,
+    Line(u32),
+    Count,
+}
+
+/// The events a hook is interested in.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HookMask {
+    pub calls: bool,
+    pub lines: bool,
+    pub count: Option<u32>,
+}
+
+impl HookMask {
+    fn accepts(&self, event: HookEvent) -> bool {
+        match event {
+            HookEvent::Call | HookEvent::Return => self.calls,
+            HookEvent::Line(_) => self.lines,
+            HookEvent::Count => self.count.is_some(),
+        }
+    }
+}
+
+type HookFn = Box<dyn FnMut(Context, HookEvent) -> Result<(), Error> + Send>;
+
+/// The hooks installed on the VM of an `Actor`, by name.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    hooks: Arc<Mutex<HashMap<&'static str, (HookMask, HookFn)>>>,
+}
+
+impl Hooks {
+    pub fn set(
+        &self,
+        lua: Context,
+        name: &'static str,
+        mask: HookMask,
+        func: impl FnMut(Context, HookEvent) -> Result<(), Error> + Send + 'static,
+    ) -> Result<(), Error> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .insert(name, (mask, Box::new(func)));
+        self.install(lua)
+    }
+    pub fn remove(&self, lua: Context, name: &'static str) -> Result<(), Error> {
+        self.hooks.lock().unwrap().remove(name);
+        self.install(lua)
+    }
+
+    fn install(&self, lua: Context) -> Result<(), Error> {
+        let sethook = debug_lib(lua)?.get::<_, Function>("sethook")?;
+        let masks = self
+            .hooks
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(mask, _)| *mask)
+            .collect::<Vec<_>>();
+        if masks.is_empty() {
+            return sethook.call(());
+        }
+
+        let mut mask = String::new();
+        if masks.iter().any(|m| m.calls) {
+            mask.push_str("cr");
+        }
+        if masks.iter().any(|m| m.lines) {
+            mask.push('l');
+        }
+        let count = masks.iter().filter_map(|m| m.count).min().unwrap_or(0);
+
+        let hooks = self.hooks.clone();
+        let hook = lua.create_function(move |lua, (event, line): (String, Option<u32>)| {
+            let event = match event.as_str() {
+                "call" | "tail call" => HookEvent::Call,
+                "return" => HookEvent::Return,
+                "line" => HookEvent::Line(line.unwrap_or(0)),
+                _ => HookEvent::Count,
+            };
+            for (mask, func) in hooks.lock().unwrap().values_mut() {
+                if mask.accepts(event) {
+                    func(lua, event)?;
+                }
+            }
+            Ok(())
+        })?;
+        sethook.call((hook, mask, count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actor::Actor;
+
+    #[test]
+    fn debug_lib_only_for_hooks() {
+        let act = Actor::new_with_handler(None);
+        let loaded = || {
+            act.lua()
+                .lock()
+                .unwrap()
+                .context(|lua| debug_lib(lua).is_ok())
+        };
+        assert!(!loaded());
+
+        let profiler = act.start_profiler(100).unwrap();
+        act.stop_profiler(profiler).unwrap();
+        assert!(loaded());
+        // Still hidden from the scripts.
+        assert_eq!(
+            Some(true),
+            Option::from(
+                act.eval("debug == nil and package.loaded.debug == nil")
+                    .unwrap()
+            )
+        );
+    }
+}
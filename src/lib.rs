@@ -1,5 +1,21 @@
 extern crate fp_rust;
+#[cfg(feature = "derive")]
+extern crate lua_actor_derive;
 extern crate rlua;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "debugger")]
+#[macro_use]
+extern crate serde_json;
 
 pub mod actor;
+pub mod coverage;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod error;
+pub mod event;
+pub mod hook;
 pub mod message;
+pub mod profiler;
+pub mod store;
+pub mod testing;
@@ -3,22 +3,192 @@ Credits(mainly, except Array & reverse conversions):
 https://github.com/poga/actix-lua/blob/master/src/message.rs
 */
 
-use std::collections::{HashMap, VecDeque};
+use std::any::{self, Any};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use rlua::Result as LuaResult;
-use rlua::{Context, FromLua, MultiValue, ToLua, ToLuaMulti, Value, Variadic};
+use rlua::{
+    AnyUserData, Context, Error, FromLua, FromLuaMulti, Function, MultiValue, RegistryKey, Table,
+    ToLua, ToLuaMulti, UserData, Value, Variadic,
+};
 
+#[cfg(feature = "derive")]
+pub use lua_actor_derive::{FromLuaMessage, IntoLuaMessage};
+#[cfg(feature = "serde")]
+mod serde_impls;
+#[cfg(feature = "serde")]
+pub(crate) use self::serde_impls::to_multi_message;
+#[cfg(feature = "serde")]
+pub use self::serde_impls::{from_message, to_message, MessageError};
+
+/// A Lua value copied out of (or into) a Lua VM.
+///
+/// Lua strings which aren't UTF-8 (e.g. packed binary data) are `Bytes`.
+/// Tables with string keys only are `Table`, the other ones (e.g. `{[10] = 1, [true] = 2}`) are `Map`.
+/// Tables with positive integer keys are `Array` (with `Nil` in the holes, e.g. `{1, nil, 3}`),
+/// or `SparseArray` when there are too many holes (see `ConversionOptions::max_hole_ratio`).
+///
+/// With the `serde` feature, it's (de)serialized as: `String`→string, `Bytes`→bytes, `Integer`→i64,
+/// `Number`→f64, `Boolean`→bool, `Nil`→unit, `Table`, `Map` & `SparseArray`→map, `Array` & `Variadic`→seq;
+/// `UserData`, `Function` & `Handle` can't be serialized.
+/// Integers & floats stay apart, unsigned integers beyond `i64` become `Number`,
+/// maps with keys other than strings are deserialized as `Map`, and options are `Nil` or their value.
+/// `VariadicLuaMessage` & `MultiLuaMessage` are the seq of their values.
 #[derive(Debug, PartialEq, Clone)]
 pub enum LuaMessage {
     String(String),
+    Bytes(Vec<u8>),
     Integer(i64),
     Number(f64),
     Boolean(bool),
     Nil,
     Table(HashMap<String, LuaMessage>),
+    Map(HashMap<LuaKey, LuaMessage>),
     Array(VecDeque<LuaMessage>),
+    SparseArray(BTreeMap<i64, LuaMessage>),
     Variadic(VariadicLuaMessage),
+    UserData(LuaUserData),
+    Function(LuaFunctionRef),
+    Handle(LuaHandle),
+}
+
+/// The Lua type name of a value (as `type()` in Lua).
+pub fn lua_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "boolean",
+        Value::LightUserData(_) => "userdata",
+        Value::Integer(_) | Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        Value::UserData(_) => "userdata",
+        Value::Error(_) => "error",
+    }
+}
+
+/// A key of `LuaMessage::Map`: any value but `Nil` & NaN, as the keys of Lua tables.
+///
+/// Floats with an integral value are the same key as the integer (`t[1.0]` is `t[1]`),
+/// and `Bytes` which are UTF-8 the same key as the `String`.
+/// Tables, functions & userdata are keys by identity, as in Lua: they are `Handle`s
+/// (converting a Lua table keyed by tables makes handles of its keys).
+#[derive(Clone)]
+pub struct LuaKey(Key);
+
+// The messages which are keys. The registry key of a handle is opaque (`Any`),
+// as only its ids (of the VM & in the VM) take part in the identity.
+#[derive(Clone)]
+enum Key {
+    String(String),
+    Bytes(Vec<u8>),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+    Handle(u64, i64, &'static str, Arc<dyn Any + Send + Sync>),
+}
+
+impl LuaKey {
+    /// The key of a value, or `None` for `Nil`, NaN, and the tables, functions & userdata
+    /// which aren't `Handle`s (they have no identity once copied out of the VM).
+    pub fn new(key: impl Into<LuaMessage>) -> Option<LuaKey> {
+        Some(LuaKey(match key.into() {
+            LuaMessage::String(s) => Key::String(s),
+            LuaMessage::Bytes(b) => match String::from_utf8(b) {
+                Ok(s) => Key::String(s),
+                Err(err) => Key::Bytes(err.into_bytes()),
+            },
+            LuaMessage::Integer(i) => Key::Integer(i),
+            LuaMessage::Number(n) if n.is_nan() => return None,
+            LuaMessage::Number(n)
+                if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 =>
+            {
+                Key::Integer(n as i64)
+            }
+            LuaMessage::Number(n) => Key::Number(n),
+            LuaMessage::Boolean(b) => Key::Boolean(b),
+            LuaMessage::Handle(handle) => {
+                Key::Handle(handle.vm, handle.id, handle.type_name, handle.key)
+            }
+            _ => return None,
+        }))
+    }
+
+    pub fn message(&self) -> LuaMessage {
+        self.clone().into_message()
+    }
+    pub fn into_message(self) -> LuaMessage {
+        match self.0 {
+            Key::String(s) => LuaMessage::String(s),
+            Key::Bytes(b) => LuaMessage::Bytes(b),
+            Key::Integer(i) => LuaMessage::Integer(i),
+            Key::Number(n) => LuaMessage::Number(n),
+            Key::Boolean(b) => LuaMessage::Boolean(b),
+            Key::Handle(vm, id, type_name, key) => LuaMessage::Handle(LuaHandle {
+                key: key
+                    .downcast::<RegistryKey>()
+                    .expect("the handles of keys keep their registry key"),
+                type_name,
+                vm,
+                id,
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for LuaKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaKey({:?})", self.message())
+    }
+}
+
+impl PartialEq for LuaKey {
+    fn eq(&self, other: &LuaKey) -> bool {
+        match (&self.0, &other.0) {
+            (Key::String(a), Key::String(b)) => a == b,
+            (Key::Bytes(a), Key::Bytes(b)) => a == b,
+            (Key::Integer(a), Key::Integer(b)) => a == b,
+            (Key::Number(a), Key::Number(b)) => a == b,
+            (Key::Boolean(a), Key::Boolean(b)) => a == b,
+            (Key::Handle(a_vm, a, a_type, _), Key::Handle(b_vm, b, b_type, _)) => {
+                a_vm == b_vm && a == b && a_type == b_type
+            }
+            _ => false,
+        }
+    }
+}
+// Reflexive: NaN isn't a key, and the integral floats (so `-0.0`) are integers.
+impl Eq for LuaKey {}
+
+impl Hash for LuaKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(&self.0).hash(state);
+        match self.0 {
+            Key::String(ref s) => s.hash(state),
+            Key::Bytes(ref b) => b.hash(state),
+            Key::Integer(i) => i.hash(state),
+            Key::Number(n) => n.to_bits().hash(state),
+            Key::Boolean(b) => b.hash(state),
+            Key::Handle(vm, id, _, _) => {
+                vm.hash(state);
+                id.hash(state);
+            }
+        }
+    }
+}
+
+impl From<LuaKey> for LuaMessage {
+    fn from(s: LuaKey) -> Self {
+        s.into_message()
+    }
 }
 
 impl LuaMessage {
@@ -29,6 +199,151 @@ impl LuaMessage {
                 .collect::<Vec<LuaMessage>>(),
         )
     }
+
+    /// The Lua type of the message (as `type()` in Lua), but `array` & `values` (for the errors).
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaMessage::String(_) | LuaMessage::Bytes(_) => "string",
+            LuaMessage::Integer(_) | LuaMessage::Number(_) => "number",
+            LuaMessage::Boolean(_) => "boolean",
+            LuaMessage::Nil => "nil",
+            LuaMessage::Table(_) | LuaMessage::Map(_) | LuaMessage::SparseArray(_) => "table",
+            LuaMessage::Array(_) => "array",
+            LuaMessage::Variadic(_) => "values",
+            LuaMessage::UserData(_) => "userdata",
+            LuaMessage::Function(_) => "function",
+            LuaMessage::Handle(handle) => handle.type_name(),
+        }
+    }
+
+    /// The lenient conversion (as `Option<T>::from`, e.g. `1` is `true` & `"1"` is `1`),
+    /// rather than the strict one of `T::try_from`.
+    pub fn coerce<T>(self) -> Result<T, ConversionError>
+    where
+        Option<T>: From<LuaMessage>,
+        T: ConversionTarget,
+    {
+        let found = self.type_name();
+        Option::<T>::from(self).ok_or(ConversionError {
+            expected: T::NAME,
+            found,
+        })
+    }
+}
+
+// Whether the string could be a key without brackets (`{name = 1}`).
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Numbers first (in order), then strings, booleans & the other keys, by their text.
+fn key_order(a: &LuaKey, b: &LuaKey) -> std::cmp::Ordering {
+    fn rank(key: &LuaKey) -> (u8, Option<f64>, String) {
+        match key.message() {
+            LuaMessage::Integer(i) => (0, Some(i as f64), String::new()),
+            LuaMessage::Number(n) => (0, Some(n), String::new()),
+            LuaMessage::String(s) => (1, None, s),
+            LuaMessage::Boolean(b) => (2, None, b.to_string()),
+            key => (3, None, key.to_string()),
+        }
+    }
+    let (a, b) = (rank(a), rank(b));
+    a.0.cmp(&b.0)
+        .then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .then(a.2.cmp(&b.2))
+}
+
+/// Lua-like text of the message, e.g. `{1, 2}`, `{name = "demo"}` or `{[10] = true}`.
+impl fmt::Display for LuaMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn nested(f: &mut fmt::Formatter, message: &LuaMessage) -> fmt::Result {
+            match message {
+                LuaMessage::String(s) => write!(f, "{:?}", s),
+                LuaMessage::Bytes(b) => write!(f, "\"{}\"", b.escape_ascii()),
+                message => write!(f, "{}", message),
+            }
+        }
+        fn list<'a>(
+            f: &mut fmt::Formatter,
+            items: impl Iterator<Item = &'a LuaMessage>,
+        ) -> fmt::Result {
+            for (i, item) in items.enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                nested(f, item)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            LuaMessage::String(s) => write!(f, "{}", s),
+            LuaMessage::Bytes(b) => write!(f, "{}", b.escape_ascii()),
+            LuaMessage::Integer(i) => write!(f, "{}", i),
+            LuaMessage::Number(n) => write!(f, "{:?}", n),
+            LuaMessage::Boolean(b) => write!(f, "{}", b),
+            LuaMessage::Nil => write!(f, "nil"),
+            LuaMessage::Table(table) => {
+                let mut keys = table.keys().collect::<Vec<_>>();
+                keys.sort();
+                write!(f, "{{")?;
+                for (i, key) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} = ", key)?;
+                    nested(f, &table[key])?;
+                }
+                write!(f, "}}")
+            }
+            LuaMessage::Map(map) => {
+                let mut entries = map.iter().collect::<Vec<_>>();
+                entries.sort_by(|(a, _), (b, _)| key_order(a, b));
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match key.message() {
+                        LuaMessage::String(ref s) if is_name(s) => write!(f, "{} = ", s)?,
+                        ref key => {
+                            write!(f, "[")?;
+                            nested(f, key)?;
+                            write!(f, "] = ")?;
+                        }
+                    }
+                    nested(f, value)?;
+                }
+                write!(f, "}}")
+            }
+            LuaMessage::Array(array) => {
+                write!(f, "{{")?;
+                list(f, array.iter())?;
+                write!(f, "}}")
+            }
+            LuaMessage::SparseArray(array) => {
+                write!(f, "{{")?;
+                for (i, (index, value)) in array.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "[{}] = ", index)?;
+                    nested(f, value)?;
+                }
+                write!(f, "}}")
+            }
+            LuaMessage::Variadic(variadic) => list(f, variadic.0.iter()),
+            LuaMessage::UserData(userdata) => write!(f, "userdata: {}", userdata.type_name()),
+            LuaMessage::Function(_) => write!(f, "function"),
+            LuaMessage::Handle(handle) => write!(f, "{}: {}", handle.type_name(), handle.id()),
+        }
+    }
 }
 
 impl From<bool> for LuaMessage {
@@ -39,17 +354,20 @@ impl From<bool> for LuaMessage {
 impl From<LuaMessage> for Option<bool> {
     fn from(s: LuaMessage) -> Self {
         match s {
-            LuaMessage::String(s) => match s.parse::<bool>() {
-                Ok(_x) => Some(_x),
-                Err(_e) => None,
-            },
+            LuaMessage::String(s) => s.parse::<bool>().ok(),
+            LuaMessage::Bytes(b) => std::str::from_utf8(&b).ok()?.parse::<bool>().ok(),
             LuaMessage::Integer(i) => Some(i > 0),
             LuaMessage::Number(f) => Some(f > 0_f64),
             LuaMessage::Boolean(b) => Some(b),
             LuaMessage::Nil => None,
             LuaMessage::Table(_h) => Some(!_h.is_empty()),
+            LuaMessage::Map(_h) => Some(!_h.is_empty()),
             LuaMessage::Array(_h) => Some(!_h.is_empty()),
+            LuaMessage::SparseArray(_h) => Some(!_h.is_empty()),
             LuaMessage::Variadic(_h) => Some(!_h.0.is_empty()),
+            LuaMessage::UserData(_) => Some(true),
+            LuaMessage::Function(_) => Some(true),
+            LuaMessage::Handle(_) => Some(true),
         }
     }
 }
@@ -68,13 +386,44 @@ impl From<LuaMessage> for Option<String> {
     fn from(s: LuaMessage) -> Self {
         match s {
             LuaMessage::String(s) => Some(s),
+            LuaMessage::Bytes(b) => String::from_utf8(b).ok(),
             LuaMessage::Integer(i) => Some(i.to_string()),
             LuaMessage::Number(f) => Some(f.to_string()),
             LuaMessage::Boolean(b) => Some(b.to_string()),
             LuaMessage::Nil => None,
             LuaMessage::Table(_h) => Some(format!("{:?}", _h)),
+            LuaMessage::Map(_h) => Some(format!("{:?}", _h)),
             LuaMessage::Array(_h) => Some(format!("{:?}", _h)),
+            LuaMessage::SparseArray(_h) => Some(format!("{:?}", _h)),
             LuaMessage::Variadic(_h) => Some(format!("{:?}", _h.0)),
+            LuaMessage::UserData(_u) => Some(format!("{:?}", _u)),
+            LuaMessage::Function(_f) => Some(format!("{:?}", _f)),
+            LuaMessage::Handle(_h) => Some(format!("{:?}", _h)),
+        }
+    }
+}
+
+impl From<Vec<u8>> for LuaMessage {
+    fn from(s: Vec<u8>) -> Self {
+        LuaMessage::Bytes(s)
+    }
+}
+impl<'l> From<&'l [u8]> for LuaMessage {
+    fn from(s: &'l [u8]) -> Self {
+        LuaMessage::Bytes(s.to_vec())
+    }
+}
+impl From<Vec<u8>> for MultiLuaMessage {
+    fn from(s: Vec<u8>) -> Self {
+        LuaMessage::from(s).into()
+    }
+}
+impl From<LuaMessage> for Option<Vec<u8>> {
+    fn from(s: LuaMessage) -> Self {
+        match s {
+            LuaMessage::String(s) => Some(s.into_bytes()),
+            LuaMessage::Bytes(b) => Some(b),
+            _ => None,
         }
     }
 }
@@ -88,22 +437,288 @@ impl VariadicLuaMessage {
     }
 }
 
+/// A shared Rust object implementing `rlua::UserData`, carried by `LuaMessage::UserData`.
+///
+/// Lua receives its own copy of the object (hence `Clone`), with all of its methods.
+/// When the userdata comes back from Lua, its current state is copied out again,
+/// so the changes made by Lua methods are kept.
+#[derive(Clone)]
+pub struct LuaUserData {
+    value: Arc<dyn Any + Send + Sync>,
+    vtable: UserDataVTable,
+}
+
+#[derive(Clone, Copy)]
+struct UserDataVTable {
+    type_name: &'static str,
+    to_lua: for<'lua> fn(&LuaUserData, Context<'lua>) -> LuaResult<AnyUserData<'lua>>,
+    from_lua: for<'lua> fn(&AnyUserData<'lua>) -> LuaResult<Arc<dyn Any + Send + Sync>>,
+}
+
+// Attached as the user value of the userdata created from `LuaUserData`,
+// so the Rust type could be found again.
+impl UserData for UserDataVTable {}
+
+impl LuaUserData {
+    pub fn new<T: UserData + Clone + Send + Sync + 'static>(value: T) -> Self {
+        LuaUserData {
+            value: Arc::new(value),
+            vtable: UserDataVTable {
+                type_name: any::type_name::<T>(),
+                to_lua: Self::to_lua_typed::<T>,
+                from_lua: Self::from_lua_typed::<T>,
+            },
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.vtable.type_name
+    }
+    pub fn is<T: 'static>(&self) -> bool {
+        self.value.is::<T>()
+    }
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        self.value.downcast_ref::<T>()
+    }
+    pub fn downcast<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.value.clone().downcast::<T>().ok()
+    }
+
+    fn to_lua_typed<'lua, T: UserData + Clone + Send + Sync + 'static>(
+        &self,
+        lua: Context<'lua>,
+    ) -> LuaResult<AnyUserData<'lua>> {
+        let ud = lua.create_userdata(self.downcast_ref::<T>().unwrap().clone())?;
+        ud.set_user_value(self.vtable)?;
+        Ok(ud)
+    }
+    fn from_lua_typed<T: UserData + Clone + Send + Sync + 'static>(
+        ud: &AnyUserData,
+    ) -> LuaResult<Arc<dyn Any + Send + Sync>> {
+        Ok(Arc::new(ud.borrow::<T>()?.clone()))
+    }
+
+    // `None` when the userdata wasn't created from `LuaUserData`.
+    fn from_any_userdata(ud: &AnyUserData) -> LuaResult<Option<Self>> {
+        let vtable = match ud.get_user_value::<Value>()? {
+            Value::UserData(v) if v.is::<UserDataVTable>() => *v.borrow::<UserDataVTable>()?,
+            _ => return Ok(None),
+        };
+        Ok(Some(LuaUserData {
+            value: (vtable.from_lua)(ud)?,
+            vtable,
+        }))
+    }
+}
+
+impl fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaUserData({})", self.type_name())
+    }
+}
+
+impl PartialEq for LuaUserData {
+    fn eq(&self, other: &LuaUserData) -> bool {
+        Arc::as_ptr(&self.value) as *const () == Arc::as_ptr(&other.value) as *const ()
+    }
+}
+
+impl From<LuaUserData> for LuaMessage {
+    fn from(s: LuaUserData) -> Self {
+        LuaMessage::UserData(s)
+    }
+}
+impl From<LuaMessage> for Option<LuaUserData> {
+    fn from(s: LuaMessage) -> Self {
+        match s {
+            LuaMessage::UserData(u) => Some(u),
+            _ => None,
+        }
+    }
+}
+impl From<LuaUserData> for MultiLuaMessage {
+    fn from(s: LuaUserData) -> Self {
+        LuaMessage::from(s).into()
+    }
+}
+
+/// A Lua function kept in the registry of the Lua VM which created it.
+///
+/// It could be stored in Rust and called later (see `Actor::call_function`).
+/// The registry value is removed when the last clone is released; once the last clone is
+/// just dropped (on any thread), it's removed the next time a `LuaFunctionRef` or `LuaHandle`
+/// is made in the VM (or by `Actor::release_function`).
+#[derive(Clone)]
+pub struct LuaFunctionRef(Arc<RegistryKey>);
+
+impl LuaFunctionRef {
+    pub fn new<'lua>(lua: Context<'lua>, func: Function<'lua>) -> LuaResult<Self> {
+        // The registry keys dropped are queued by rlua, until expired here.
+        lua.expire_registry_values();
+        Ok(LuaFunctionRef(Arc::new(lua.create_registry_value(func)?)))
+    }
+
+    pub fn function<'lua>(&self, lua: Context<'lua>) -> LuaResult<Function<'lua>> {
+        lua.registry_value(&self.0)
+    }
+    pub fn release(self, lua: Context) -> LuaResult<()> {
+        match Arc::try_unwrap(self.0) {
+            Ok(key) => lua.remove_registry_value(key),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for LuaFunctionRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaFunctionRef({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+impl PartialEq for LuaFunctionRef {
+    fn eq(&self, other: &LuaFunctionRef) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl From<LuaFunctionRef> for LuaMessage {
+    fn from(s: LuaFunctionRef) -> Self {
+        LuaMessage::Function(s)
+    }
+}
+impl From<LuaMessage> for Option<LuaFunctionRef> {
+    fn from(s: LuaMessage) -> Self {
+        match s {
+            LuaMessage::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+impl From<LuaFunctionRef> for MultiLuaMessage {
+    fn from(s: LuaFunctionRef) -> Self {
+        LuaMessage::from(s).into()
+    }
+}
+
+const HANDLES_KEY: &str = "lua_actor.handles";
+
+// The ids of the VMs, for the handles (`Lua` has none).
+static LAST_VM_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A Lua value without a `LuaMessage` (a thread, light userdata, userdata not created from
+/// `LuaUserData`, an error, or a key of `LuaKey` by identity),
+/// kept in the registry of the Lua VM which created it.
+///
+/// The handles of the same Lua value have the same `id` in the VM, and are equal
+/// (the handles of different VMs never are, whatever their ids).
+/// As `LuaFunctionRef`, the registry value is removed when the last clone is released
+/// (or dropped, the next time a `LuaFunctionRef` or `LuaHandle` is made).
+#[derive(Clone)]
+pub struct LuaHandle {
+    key: Arc<RegistryKey>,
+    type_name: &'static str,
+    vm: u64,
+    id: i64,
+}
+
+impl LuaHandle {
+    pub fn new<'lua>(lua: Context<'lua>, value: Value<'lua>) -> LuaResult<Self> {
+        // The ids by value, not keeping the values alive.
+        let ids = match lua.named_registry_value::<_, Value>(HANDLES_KEY)? {
+            Value::Table(t) => t,
+            _ => {
+                let t = lua.create_table()?;
+                let metatable = lua.create_table()?;
+                metatable.set("__mode", "k")?;
+                t.set_metatable(Some(metatable));
+                t.raw_set("vm", LAST_VM_ID.fetch_add(1, Ordering::Relaxed) as i64 + 1)?;
+                lua.set_named_registry_value(HANDLES_KEY, t.clone())?;
+                t
+            }
+        };
+        let id = match ids.raw_get::<_, Option<i64>>(value.clone())? {
+            Some(id) => id,
+            None => {
+                let id = ids.raw_get::<_, Option<i64>>("last")?.unwrap_or(0) + 1;
+                ids.raw_set("last", id)?;
+                ids.raw_set(value.clone(), id)?;
+                id
+            }
+        };
+        lua.expire_registry_values();
+        Ok(LuaHandle {
+            type_name: lua_type_name(&value),
+            vm: ids.raw_get::<_, i64>("vm")? as u64,
+            key: Arc::new(lua.create_registry_value(value)?),
+            id,
+        })
+    }
+
+    /// The Lua type name of the value (as `type()` in Lua).
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+    /// The id of the value in its VM.
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+    pub fn value<'lua>(&self, lua: Context<'lua>) -> LuaResult<Value<'lua>> {
+        lua.registry_value(&self.key)
+    }
+    pub fn release(self, lua: Context) -> LuaResult<()> {
+        match Arc::try_unwrap(self.key) {
+            Ok(key) => lua.remove_registry_value(key),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for LuaHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaHandle({}: {})", self.type_name, self.id)
+    }
+}
+
+impl PartialEq for LuaHandle {
+    fn eq(&self, other: &LuaHandle) -> bool {
+        self.vm == other.vm && self.id == other.id && self.type_name == other.type_name
+    }
+}
+
+impl From<LuaHandle> for LuaMessage {
+    fn from(s: LuaHandle) -> Self {
+        LuaMessage::Handle(s)
+    }
+}
+impl From<LuaMessage> for Option<LuaHandle> {
+    fn from(s: LuaMessage) -> Self {
+        match s {
+            LuaMessage::Handle(h) => Some(h),
+            _ => None,
+        }
+    }
+}
+impl From<LuaHandle> for MultiLuaMessage {
+    fn from(s: LuaHandle) -> Self {
+        LuaMessage::from(s).into()
+    }
+}
+
 impl PartialEq<VariadicLuaMessage> for VariadicLuaMessage {
     fn eq(&self, other: &VariadicLuaMessage) -> bool {
-        let other = &other.0;
-        return self.0.eq(other);
+        self.0.eq(&other.0)
     }
 }
 
 impl From<VecDeque<LuaMessage>> for LuaMessage {
     fn from(s: VecDeque<LuaMessage>) -> Self {
-        LuaMessage::from_iter(s.into_iter())
+        LuaMessage::from_iter(s)
     }
 }
 
-impl Into<VecDeque<LuaMessage>> for VariadicLuaMessage {
-    fn into(self) -> VecDeque<LuaMessage> {
-        return self.0;
+impl From<VariadicLuaMessage> for VecDeque<LuaMessage> {
+    fn from(s: VariadicLuaMessage) -> Self {
+        s.0
     }
 }
 
@@ -114,7 +729,7 @@ impl From<VariadicLuaMessage> for LuaMessage {
 }
 impl From<Variadic<LuaMessage>> for LuaMessage {
     fn from(s: Variadic<LuaMessage>) -> Self {
-        LuaMessage::from(s.into_iter().map(|v| v).collect::<Vec<LuaMessage>>())
+        LuaMessage::from(s.into_iter().collect::<Vec<LuaMessage>>())
     }
 }
 
@@ -179,12 +794,21 @@ lua_message_convert_from_collection_variants_and_types!(String);
 lua_message_convert_from_collection_variants_and_types!(&str);
 lua_message_convert_from_collection_variants_and_types!(bool);
 lua_message_convert_from_collection_variants_and_types!(i8);
-lua_message_convert_from_collection_variants_and_types!(u8);
+// `Vec<u8>` is `Bytes`.
+lua_message_convert_from_collection!(Variadic, u8);
+lua_message_convert_from_collection_option!(Vec, u8);
+lua_message_convert_from_collection_option!(Variadic, u8);
+impl From<u8> for MultiLuaMessage {
+    fn from(s: u8) -> Self {
+        LuaMessage::from(s).into()
+    }
+}
 lua_message_convert_from_collection_variants_and_types!(i16);
 lua_message_convert_from_collection_variants_and_types!(u16);
 lua_message_convert_from_collection_variants_and_types!(i32);
 lua_message_convert_from_collection_variants_and_types!(u32);
 lua_message_convert_from_collection_variants_and_types!(i64);
+lua_message_convert_from_collection_variants_and_types!(u64);
 lua_message_convert_from_collection_variants_and_types!(isize);
 lua_message_convert_from_collection_variants_and_types!(usize);
 lua_message_convert_from_collection_variants_and_types!(f32);
@@ -194,9 +818,9 @@ lua_message_convert_from_collection_variants_only!(Vec<LuaMessage>);
 lua_message_convert_from_collection_variants_only!(VariadicLuaMessage);
 lua_message_convert_from_collection_variants_only!(Variadic<LuaMessage>);
 
-impl Into<Variadic<LuaMessage>> for LuaMessage {
-    fn into(self) -> Variadic<LuaMessage> {
-        return Variadic::from_iter([self]);
+impl From<LuaMessage> for Variadic<LuaMessage> {
+    fn from(s: LuaMessage) -> Self {
+        Variadic::from_iter([s])
     }
 }
 
@@ -209,6 +833,7 @@ macro_rules! lua_message_number_convert {
                         Ok(_x) => Some(_x),
                         Err(_e) => None,
                     },
+                    LuaMessage::Bytes(b) => std::str::from_utf8(&b).ok()?.parse::<$x>().ok(),
                     LuaMessage::Integer(i) => Some(i as $x),
                     LuaMessage::Number(f) => Some(f as $x),
                     LuaMessage::Boolean(b) => {
@@ -220,8 +845,13 @@ macro_rules! lua_message_number_convert {
                     }
                     LuaMessage::Nil => None,
                     LuaMessage::Table(_h) => None,
+                    LuaMessage::Map(_h) => None,
                     LuaMessage::Array(_h) => None,
+                    LuaMessage::SparseArray(_h) => None,
                     LuaMessage::Variadic(_h) => None,
+                    LuaMessage::UserData(_u) => None,
+                    LuaMessage::Function(_f) => None,
+                    LuaMessage::Handle(_h) => None,
                 }
             }
         }
@@ -260,6 +890,19 @@ impl From<isize> for LuaMessage {
     }
 }
 lua_message_number_convert!(isize);
+// 2^64, the float of `u64::MAX` (the nearest one).
+const U64_END: f64 = 18_446_744_073_709_551_616.0;
+
+// Beyond `i64::MAX`, the nearest float (as `serialize_u64`), which converts back saturated.
+impl From<u64> for LuaMessage {
+    fn from(s: u64) -> Self {
+        match i64::try_from(s) {
+            Ok(i) => LuaMessage::Integer(i),
+            Err(_) => LuaMessage::Number(s as f64),
+        }
+    }
+}
+lua_message_number_convert!(u64);
 
 impl From<HashMap<String, LuaMessage>> for LuaMessage {
     fn from(s: HashMap<String, LuaMessage>) -> Self {
@@ -274,6 +917,11 @@ impl From<LuaMessage> for Option<HashMap<String, LuaMessage>> {
                 h.insert("x".to_string(), LuaMessage::from(s));
                 Some(h)
             }
+            LuaMessage::Bytes(b) => {
+                let mut h = HashMap::default();
+                h.insert("x".to_string(), LuaMessage::from(b));
+                Some(h)
+            }
             LuaMessage::Integer(i) => {
                 let mut h = HashMap::default();
                 h.insert("x".to_string(), LuaMessage::from(i));
@@ -291,6 +939,10 @@ impl From<LuaMessage> for Option<HashMap<String, LuaMessage>> {
             }
             LuaMessage::Nil => None,
             LuaMessage::Table(_h) => Some(_h),
+            LuaMessage::Map(_h) => _h
+                .into_iter()
+                .map(|(k, v)| Some((Option::<String>::from(k.into_message())?, v)))
+                .collect(),
             LuaMessage::Array(_h) => {
                 let mut new_one = HashMap::default();
                 for (k, v) in _h.into_iter().enumerate() {
@@ -298,6 +950,13 @@ impl From<LuaMessage> for Option<HashMap<String, LuaMessage>> {
                 }
                 Some(new_one)
             }
+            LuaMessage::SparseArray(_h) => {
+                let mut new_one = HashMap::default();
+                for (k, v) in _h {
+                    new_one.insert(k.to_string(), v);
+                }
+                Some(new_one)
+            }
             LuaMessage::Variadic(_h) => {
                 let mut new_one = HashMap::default();
                 for (k, v) in _h.0.into_iter().enumerate() {
@@ -305,9 +964,82 @@ impl From<LuaMessage> for Option<HashMap<String, LuaMessage>> {
                 }
                 Some(new_one)
             }
+            LuaMessage::UserData(u) => {
+                let mut h = HashMap::default();
+                h.insert("x".to_string(), LuaMessage::from(u));
+                Some(h)
+            }
+            LuaMessage::Function(f) => {
+                let mut h = HashMap::default();
+                h.insert("x".to_string(), LuaMessage::from(f));
+                Some(h)
+            }
+            LuaMessage::Handle(handle) => {
+                let mut h = HashMap::default();
+                h.insert("x".to_string(), LuaMessage::from(handle));
+                Some(h)
+            }
+        }
+    }
+}
+impl From<HashMap<LuaKey, LuaMessage>> for LuaMessage {
+    fn from(s: HashMap<LuaKey, LuaMessage>) -> Self {
+        LuaMessage::Map(s)
+    }
+}
+impl From<LuaMessage> for Option<HashMap<LuaKey, LuaMessage>> {
+    fn from(s: LuaMessage) -> Self {
+        let entries = |items: VecDeque<LuaMessage>| {
+            items
+                .into_iter()
+                .enumerate()
+                .filter(|(_, v)| *v != LuaMessage::Nil)
+                .filter_map(|(i, v)| Some((LuaKey::new(i as i64 + 1)?, v)))
+                .collect()
+        };
+        match s {
+            LuaMessage::Table(_h) => Some(
+                _h.into_iter()
+                    .map(|(k, v)| (LuaKey(Key::String(k)), v))
+                    .collect(),
+            ),
+            LuaMessage::Map(_h) => Some(_h),
+            LuaMessage::Array(_h) => Some(entries(_h)),
+            LuaMessage::SparseArray(_h) => Some(
+                _h.into_iter()
+                    .map(|(k, v)| (LuaKey(Key::Integer(k)), v))
+                    .collect(),
+            ),
+            LuaMessage::Variadic(_h) => Some(entries(_h.0)),
+            _ => None,
+        }
+    }
+}
+
+impl From<BTreeMap<i64, LuaMessage>> for LuaMessage {
+    fn from(s: BTreeMap<i64, LuaMessage>) -> Self {
+        LuaMessage::SparseArray(s)
+    }
+}
+impl From<LuaMessage> for Option<BTreeMap<i64, LuaMessage>> {
+    fn from(s: LuaMessage) -> Self {
+        let entries = |items: VecDeque<LuaMessage>| {
+            items
+                .into_iter()
+                .enumerate()
+                .filter(|(_, v)| *v != LuaMessage::Nil)
+                .map(|(i, v)| (i as i64 + 1, v))
+                .collect()
+        };
+        match s {
+            LuaMessage::SparseArray(_h) => Some(_h),
+            LuaMessage::Array(_h) => Some(entries(_h)),
+            LuaMessage::Variadic(_h) => Some(entries(_h.0)),
+            _ => None,
         }
     }
 }
+
 impl From<Vec<LuaMessage>> for LuaMessage {
     fn from(s: Vec<LuaMessage>) -> Self {
         LuaMessage::Array(VecDeque::from(s))
@@ -329,6 +1061,7 @@ impl From<LuaMessage> for Option<Vec<LuaMessage>> {
     fn from(s: LuaMessage) -> Self {
         match s {
             LuaMessage::String(s) => Some(vec![LuaMessage::from(s)]),
+            LuaMessage::Bytes(b) => Some(vec![LuaMessage::from(b)]),
             LuaMessage::Integer(i) => Some(vec![LuaMessage::from(i)]),
             LuaMessage::Number(f) => Some(vec![LuaMessage::from(f)]),
             LuaMessage::Boolean(b) => Some(vec![LuaMessage::from(b)]),
@@ -341,8 +1074,27 @@ impl From<LuaMessage> for Option<Vec<LuaMessage>> {
                 }
                 Some(new_one)
             }
+            LuaMessage::Map(_h) => {
+                let mut new_one = vec![];
+                for (_k, v) in _h {
+                    new_one.push(_k.into_message());
+                    new_one.push(v);
+                }
+                Some(new_one)
+            }
+            LuaMessage::SparseArray(_h) => {
+                let mut new_one = vec![];
+                for (_k, v) in _h {
+                    new_one.push(LuaMessage::from(_k));
+                    new_one.push(v);
+                }
+                Some(new_one)
+            }
             LuaMessage::Array(_h) => Some(_h.into()),
             LuaMessage::Variadic(_h) => Some(_h.0.into()),
+            LuaMessage::UserData(u) => Some(vec![LuaMessage::from(u)]),
+            LuaMessage::Function(f) => Some(vec![LuaMessage::from(f)]),
+            LuaMessage::Handle(h) => Some(vec![LuaMessage::from(h)]),
         }
     }
 }
@@ -361,44 +1113,587 @@ macro_rules! lua_message_convert_from_float {
 lua_message_convert_from_float!(f32);
 lua_message_convert_from_float!(f64);
 
-impl<'lua> FromLua<'lua> for LuaMessage {
-    fn from_lua(v: Value<'lua>, lua: Context<'lua>) -> LuaResult<LuaMessage> {
-        match v {
-            Value::String(x) => Ok(LuaMessage::String(String::from_lua(Value::String(x), lua)?)),
-            Value::Integer(_) => Ok(LuaMessage::Integer(lua.coerce_integer(v).unwrap().unwrap())),
-            Value::Number(_) => Ok(LuaMessage::Number(lua.coerce_number(v).unwrap().unwrap())),
-            Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
-            Value::Nil => Ok(LuaMessage::Nil),
-            Value::Table(t) => {
-                if t.len()? > 0
-                    && t.clone().pairs::<i32, LuaMessage>().count()
-                        == t.clone().sequence_values::<LuaMessage>().count()
-                {
-                    Ok(LuaMessage::Array(VecDeque::from(Vec::from_lua(
-                        Value::Table(t),
-                        lua,
-                    )?)))
-                } else {
-                    Ok(LuaMessage::Table(HashMap::from_lua(Value::Table(t), lua)?))
+/// Convert a `LuaMessage` back to a Rust type, as the `Option<T>::from(LuaMessage)` conversions.
+///
+/// Other crates can't implement `From<LuaMessage> for Option<T>`,
+/// so their types implement this instead (see `#[derive(FromLuaMessage)]`, feature `derive`).
+/// `Option<T>` takes `Nil` as `None`, and `Vec<T>` takes arrays (`SparseArray`s with `Nil`
+/// in their holes, e.g. `Vec<Option<T>>`, up to an index of 2^24).
+pub trait FromLuaMessage: Sized {
+    fn from_lua_message(message: LuaMessage) -> Option<Self>;
+}
+
+macro_rules! lua_message_from_lua_message {
+    ($($x:ty),*) => {
+        $(
+            impl FromLuaMessage for $x {
+                fn from_lua_message(message: LuaMessage) -> Option<Self> {
+                    Option::from(message)
                 }
             }
+        )*
+    };
+}
+lua_message_from_lua_message!(
+    bool, String, i8, u8, i16, u16, i32, u32, i64, u64, isize, usize, f32, f64,
+    HashMap<String, LuaMessage>, HashMap<LuaKey, LuaMessage>, BTreeMap<i64, LuaMessage>,
+    LuaUserData, LuaFunctionRef, LuaHandle
+);
 
-            _ => unimplemented!(),
+impl FromLuaMessage for LuaMessage {
+    fn from_lua_message(message: LuaMessage) -> Option<Self> {
+        Some(message)
+    }
+}
+impl<T: FromLuaMessage> FromLuaMessage for Option<T> {
+    fn from_lua_message(message: LuaMessage) -> Option<Self> {
+        match message {
+            LuaMessage::Nil => Some(None),
+            message => T::from_lua_message(message).map(Some),
+        }
+    }
+}
+impl<T: FromLuaMessage> FromLuaMessage for Vec<T> {
+    fn from_lua_message(message: LuaMessage) -> Option<Self> {
+        match message {
+            LuaMessage::Array(items) => items.into_iter().map(T::from_lua_message).collect(),
+            LuaMessage::Variadic(items) => items.0.into_iter().map(T::from_lua_message).collect(),
+            LuaMessage::Bytes(bytes) => bytes
+                .into_iter()
+                .map(|b| T::from_lua_message(LuaMessage::from(b)))
+                .collect(),
+            LuaMessage::SparseArray(items) => fill_holes(items),
+            // Empty Lua tables come as `Table`.
+            LuaMessage::Table(ref table) if table.is_empty() => Some(vec![]),
+            _ => None,
         }
     }
 }
 
-impl<'lua> ToLua<'lua> for LuaMessage {
-    fn to_lua(self, lua: Context<'lua>) -> LuaResult<Value<'lua>> {
+// The longest arrays made of `SparseArray`s, whatever their indices.
+const MAX_FILLED_LENGTH: i64 = 1 << 24;
+
+// The items of a `SparseArray` with `Nil` in the holes, if its indices are from 1
+// and the holes convert (e.g. as `Option<T>`).
+fn fill_holes<T: FromLuaMessage>(items: BTreeMap<i64, LuaMessage>) -> Option<Vec<T>> {
+    let (first, last) = match (items.keys().next(), items.keys().next_back()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => return Some(vec![]),
+    };
+    if first < 1 || last > MAX_FILLED_LENGTH {
+        return None;
+    }
+    let mut items = items.into_iter().peekable();
+    (1..=last)
+        .map(|index| match items.next_if(|(i, _)| *i == index) {
+            Some((_, item)) => T::from_lua_message(item),
+            None => T::from_lua_message(LuaMessage::Nil),
+        })
+        .collect()
+}
+
+/// The error of the strict conversions from `LuaMessage` (`T::try_from`):
+/// the Rust type expected, and the type of the message found (see `LuaMessage::type_name`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, found: &LuaMessage) -> Self {
+        ConversionError {
+            expected,
+            found: found.type_name(),
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, got {}", self.expected, self.found)
+    }
+}
+
+impl StdError for ConversionError {}
+
+/// The name of a Rust type in the `ConversionError`s (e.g. `String`, not `alloc::string::String`).
+pub trait ConversionTarget {
+    const NAME: &'static str;
+}
+
+// Whether the integer is exactly a `f64` (2^53 and beyond, not all are).
+fn exact_f64(i: i64) -> Option<f64> {
+    let f = i as f64;
+    // `i64::MAX` rounds up to 2^63, which doesn't fit back.
+    if f < i64::MAX as f64 && f as i64 == i {
+        Some(f)
+    } else {
+        None
+    }
+}
+
+// Whether the number is exactly a `f32` (neither out of its range, nor rounded).
+fn exact_f32(f: f64) -> Option<f32> {
+    if f.is_nan() || f64::from(f as f32) == f {
+        Some(f as f32)
+    } else {
+        None
+    }
+}
+
+// The strict conversions: no coercion between the Lua types, and the numbers must fit.
+macro_rules! lua_message_try_from {
+    ($x:ty, $expected:expr, $($pattern:pat $(if $guard:expr)? => $value:expr),* $(,)?) => {
+        impl ConversionTarget for $x {
+            const NAME: &'static str = $expected;
+        }
+
+        impl TryFrom<LuaMessage> for $x {
+            type Error = ConversionError;
+
+            fn try_from(s: LuaMessage) -> Result<Self, ConversionError> {
+                match s {
+                    $($pattern $(if $guard)? => $value,)*
+                    s => Err(ConversionError::new($expected, &s)),
+                }
+            }
+        }
+    };
+}
+macro_rules! lua_message_try_from_int {
+    ($($x:ty),*) => {
+        $(
+            impl ConversionTarget for $x {
+                const NAME: &'static str = stringify!($x);
+            }
+
+            impl TryFrom<LuaMessage> for $x {
+                type Error = ConversionError;
+
+                fn try_from(s: LuaMessage) -> Result<Self, ConversionError> {
+                    let integer = match s {
+                        LuaMessage::Integer(i) => Some(i),
+                        // Floats with an integral value (as `math.tointeger`).
+                        LuaMessage::Number(f)
+                            if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 =>
+                        {
+                            Some(f as i64)
+                        }
+                        _ => None,
+                    };
+                    integer
+                        .and_then(|i| <$x>::try_from(i).ok())
+                        .ok_or_else(|| ConversionError::new(Self::NAME, &s))
+                }
+            }
+        )*
+    };
+}
+lua_message_try_from_int!(i8, u8, i16, u16, i32, u32, i64, isize, usize);
+impl ConversionTarget for u64 {
+    const NAME: &'static str = "u64";
+}
+impl TryFrom<LuaMessage> for u64 {
+    type Error = ConversionError;
+
+    fn try_from(s: LuaMessage) -> Result<Self, ConversionError> {
+        match s {
+            LuaMessage::Integer(i) => u64::try_from(i).ok(),
+            // The floats of `From<u64>` (2^64 being `u64::MAX`).
+            LuaMessage::Number(f) if f.fract() == 0.0 && (0.0..=U64_END).contains(&f) => {
+                Some(f as u64)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| ConversionError::new(Self::NAME, &s))
+    }
+}
+// The numbers must be exact: no rounding, e.g. of the integers beyond 2^53 (2^24 for `f32`).
+lua_message_try_from!(f64, "f64",
+    LuaMessage::Number(f) => Ok(f),
+    LuaMessage::Integer(i) if exact_f64(i).is_some() => Ok(i as f64),
+);
+lua_message_try_from!(f32, "f32",
+    LuaMessage::Number(f) if exact_f32(f).is_some() => Ok(f as f32),
+    LuaMessage::Integer(i) if exact_f64(i).and_then(exact_f32).is_some() => Ok(i as f32),
+);
+lua_message_try_from!(bool, "bool",
+    LuaMessage::Boolean(b) => Ok(b),
+);
+lua_message_try_from!(String, "String",
+    LuaMessage::String(s) => Ok(s),
+    // Lua strings which aren't UTF-8 aren't `String`.
+    LuaMessage::Bytes(b) => String::from_utf8(b).map_err(|_| ConversionError {
+        expected: "String",
+        found: "string",
+    }),
+);
+lua_message_try_from!(Vec<u8>, "Vec<u8>",
+    LuaMessage::Bytes(b) => Ok(b),
+    LuaMessage::String(s) => Ok(s.into_bytes()),
+);
+lua_message_try_from!(Vec<LuaMessage>, "Vec<LuaMessage>",
+    LuaMessage::Array(a) => Ok(a.into()),
+    LuaMessage::Variadic(v) => Ok(v.0.into()),
+    LuaMessage::SparseArray(a) => fill_holes(a).ok_or(ConversionError {
+        expected: "Vec<LuaMessage>",
+        found: "table",
+    }),
+    // Empty Lua tables come as `Table`.
+    LuaMessage::Table(ref t) if t.is_empty() => Ok(vec![]),
+);
+lua_message_try_from!(HashMap<String, LuaMessage>, "HashMap<String, LuaMessage>",
+    LuaMessage::Table(t) => Ok(t),
+);
+lua_message_try_from!(HashMap<LuaKey, LuaMessage>, "HashMap<LuaKey, LuaMessage>",
+    s @ LuaMessage::Table(_) => Ok(Option::from(s).unwrap_or_default()),
+    LuaMessage::Map(m) => Ok(m),
+    s @ LuaMessage::SparseArray(_) => Ok(Option::from(s).unwrap_or_default()),
+    s @ LuaMessage::Array(_) => Ok(Option::from(s).unwrap_or_default()),
+);
+lua_message_try_from!(BTreeMap<i64, LuaMessage>, "BTreeMap<i64, LuaMessage>",
+    LuaMessage::SparseArray(a) => Ok(a),
+    s @ LuaMessage::Array(_) => Ok(Option::from(s).unwrap_or_default()),
+    LuaMessage::Table(ref t) if t.is_empty() => Ok(BTreeMap::new()),
+);
+lua_message_try_from!(LuaUserData, "LuaUserData",
+    LuaMessage::UserData(u) => Ok(u),
+);
+lua_message_try_from!(LuaFunctionRef, "LuaFunctionRef",
+    LuaMessage::Function(f) => Ok(f),
+);
+lua_message_try_from!(LuaHandle, "LuaHandle",
+    LuaMessage::Handle(h) => Ok(h),
+);
+
+const CONVERSION_OPTIONS_KEY: &str = "lua_actor.conversion_options";
+
+/// The limits of the conversions of Lua values to `LuaMessage` in a VM
+/// (see `set_conversion_options`, `Actor::set_conversion_options`).
+///
+/// Tables referencing themselves (e.g. `t.self = t`) are always stopped, as cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConversionOptions {
+    /// The most tables nested in each other (`{{}}` is 2).
+    pub max_depth: usize,
+    /// The most table entries in a value, nested ones included.
+    pub max_elements: usize,
+    /// `Nil` instead of the tables beyond the limits (or in a cycle), rather than an error.
+    pub lenient: bool,
+    /// The most holes (as a ratio of the greatest index) in the tables with positive integer keys
+    /// converted to `Array`, rather than `SparseArray`: `{1, nil, 3}` has 1/3. Within [0, 1).
+    pub max_hole_ratio: f64,
+    /// Whether the empty tables are `Array`, rather than `Table`.
+    pub empty_table_as_array: bool,
+    /// What the values without a `LuaMessage` (see `LuaHandle`) are converted to.
+    pub opaque: OpaquePolicy,
+}
+
+/// The conversion of the values without a `LuaMessage` (see `LuaHandle`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpaquePolicy {
+    /// `LuaMessage::Handle`.
+    Handle,
+    /// A conversion error.
+    Error,
+    /// `LuaMessage::Nil`.
+    Nil,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        ConversionOptions {
+            max_depth: 128,
+            max_elements: usize::MAX,
+            lenient: false,
+            max_hole_ratio: 0.5,
+            empty_table_as_array: false,
+            opaque: OpaquePolicy::Handle,
+        }
+    }
+}
+
+impl UserData for ConversionOptions {}
+
+/// Set the conversion options of the VM.
+///
+/// `max_hole_ratio` must be within [0, 1): the arrays would be unbounded by their entries.
+pub fn set_conversion_options(lua: Context, options: ConversionOptions) -> LuaResult<()> {
+    if !(0.0..1.0).contains(&options.max_hole_ratio) {
+        return Err(Error::RuntimeError(format!(
+            "invalid max_hole_ratio: {} (must be within [0, 1))",
+            options.max_hole_ratio
+        )));
+    }
+    lua.set_named_registry_value(CONVERSION_OPTIONS_KEY, options)
+}
+
+/// The conversion options of the VM (the default ones unless set).
+pub fn conversion_options(lua: Context) -> LuaResult<ConversionOptions> {
+    match lua.named_registry_value::<_, Value>(CONVERSION_OPTIONS_KEY)? {
+        Value::UserData(options) => Ok(*options.borrow::<ConversionOptions>()?),
+        _ => Ok(ConversionOptions::default()),
+    }
+}
+
+// Why a conversion stopped: a Lua error, or a limit reached at the path (innermost key first).
+enum Stop {
+    Lua(Error),
+    Limit(String, Vec<String>),
+}
+
+impl From<Error> for Stop {
+    fn from(err: Error) -> Self {
+        Stop::Lua(err)
+    }
+}
+
+// The key of a table entry in a path, as in Lua (`.name`, `[1]`).
+fn path_segment(key: &Value) -> String {
+    match key {
+        Value::String(s) => match s.to_str() {
+            Ok(s) if is_name(s) => format!(".{}", s),
+            Ok(s) => format!("[{:?}]", s),
+            Err(_) => format!("[\"{}\"]", s.as_bytes().escape_ascii()),
+        },
+        Value::Integer(i) => format!("[{}]", i),
+        Value::Number(n) => format!("[{:?}]", n),
+        Value::Boolean(b) => format!("[{}]", b),
+        key => format!("[{}]", lua_type_name(key)),
+    }
+}
+
+// The state of the conversion of a value.
+struct Converter<'lua> {
+    lua: Context<'lua>,
+    options: ConversionOptions,
+    depth: usize,
+    elements: usize,
+    // The tables being converted (the ones containing the current value), as a set.
+    visiting: Option<Table<'lua>>,
+}
+
+impl<'lua> Converter<'lua> {
+    fn convert(&mut self, v: Value<'lua>) -> Result<LuaMessage, Stop> {
+        let lua = self.lua;
+        match v {
+            Value::String(x) => Ok(match std::str::from_utf8(x.as_bytes()) {
+                Ok(s) => LuaMessage::String(s.to_string()),
+                Err(_) => LuaMessage::Bytes(x.as_bytes().to_vec()),
+            }),
+            Value::Integer(i) => Ok(LuaMessage::Integer(i)),
+            Value::Number(n) => Ok(LuaMessage::Number(n)),
+            Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
+            Value::Nil => Ok(LuaMessage::Nil),
+            Value::Table(t) => self.table(t),
+            Value::UserData(ud) => match LuaUserData::from_any_userdata(&ud)? {
+                Some(userdata) => Ok(LuaMessage::UserData(userdata)),
+                None => self.opaque(
+                    Value::UserData(ud),
+                    "userdata was not created from LuaUserData",
+                ),
+            },
+            Value::Function(f) => Ok(LuaMessage::Function(LuaFunctionRef::new(lua, f)?)),
+            Value::Thread(_) => self.opaque(v, "threads are not converted"),
+            Value::LightUserData(_) => self.opaque(v, "light userdata are not converted"),
+            Value::Error(_) => self.opaque(v, "errors are not converted"),
+        }
+    }
+
+    // Keys without a value of their own are kept by identity, whatever the `OpaquePolicy`.
+    fn key(&mut self, key: Value<'lua>) -> Result<LuaMessage, Stop> {
+        match key {
+            Value::Table(_)
+            | Value::Function(_)
+            | Value::UserData(_)
+            | Value::LightUserData(_)
+            | Value::Thread(_) => Ok(LuaMessage::Handle(LuaHandle::new(self.lua, key)?)),
+            key => self.convert(key),
+        }
+    }
+
+    fn opaque(&self, v: Value<'lua>, message: &str) -> Result<LuaMessage, Stop> {
+        match self.options.opaque {
+            OpaquePolicy::Handle => Ok(LuaMessage::Handle(LuaHandle::new(self.lua, v)?)),
+            OpaquePolicy::Nil => Ok(LuaMessage::Nil),
+            OpaquePolicy::Error => Err(Stop::Limit(message.to_string(), vec![])),
+        }
+    }
+
+    fn limit(&self, message: String) -> Result<LuaMessage, Stop> {
+        match self.options.lenient {
+            true => Ok(LuaMessage::Nil),
+            false => Err(Stop::Limit(message, vec![])),
+        }
+    }
+
+    fn table(&mut self, t: Table<'lua>) -> Result<LuaMessage, Stop> {
+        if self.depth >= self.options.max_depth {
+            return self.limit(format!(
+                "tables nested deeper than {}",
+                self.options.max_depth
+            ));
+        }
+        let visiting = match self.visiting {
+            Some(ref visiting) => visiting.clone(),
+            None => {
+                let visiting = self.lua.create_table()?;
+                self.visiting = Some(visiting.clone());
+                visiting
+            }
+        };
+        if visiting.raw_get::<_, bool>(t.clone())? {
+            return self.limit("cycle of tables".to_string());
+        }
+
+        visiting.raw_set(t.clone(), true)?;
+        self.depth += 1;
+        let message = self.entries(t.clone());
+        self.depth -= 1;
+        visiting.raw_set(t, Value::Nil)?;
+        message
+    }
+
+    fn entries(&mut self, t: Table<'lua>) -> Result<LuaMessage, Stop> {
+        let mut entries = Vec::new();
+        for pair in t.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            self.elements += 1;
+            if self.elements > self.options.max_elements {
+                return self.limit(format!(
+                    "more than {} table entries",
+                    self.options.max_elements
+                ));
+            }
+            let converted = self
+                .key(key.clone())
+                .and_then(|k| Ok((k, self.convert(value)?)));
+            match converted {
+                Ok(entry) => entries.push(entry),
+                Err(Stop::Limit(message, mut path)) => {
+                    path.push(path_segment(&key));
+                    return Err(Stop::Limit(message, path));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(classify(entries, &self.options))
+    }
+}
+
+// The variant of a table by its keys: positive integers are `Array` (or `SparseArray`),
+// strings are `Table`, and the other ones (or a mix) are `Map`.
+fn classify(entries: Vec<(LuaMessage, LuaMessage)>, options: &ConversionOptions) -> LuaMessage {
+    if entries.is_empty() {
+        return match options.empty_table_as_array {
+            true => LuaMessage::Array(VecDeque::new()),
+            false => LuaMessage::Table(HashMap::new()),
+        };
+    }
+
+    let mut max_index = Some(0);
+    for (key, _) in &entries {
+        max_index = match (key, max_index) {
+            (LuaMessage::Integer(i), Some(max)) if *i >= 1 => Some(max.max(*i)),
+            _ => None,
+        };
+    }
+    if let Some(max_index) = max_index {
+        let holes = (max_index - entries.len() as i64) as f64;
+        if holes <= options.max_hole_ratio * max_index as f64 {
+            let mut array = VecDeque::from(vec![LuaMessage::Nil; max_index as usize]);
+            for (key, value) in entries {
+                if let LuaMessage::Integer(i) = key {
+                    array[i as usize - 1] = value;
+                }
+            }
+            return LuaMessage::Array(array);
+        }
+        return LuaMessage::SparseArray(
+            entries
+                .into_iter()
+                .filter_map(|(key, value)| match key {
+                    LuaMessage::Integer(i) => Some((i, value)),
+                    _ => None,
+                })
+                .collect(),
+        );
+    }
+
+    if entries
+        .iter()
+        .all(|(key, _)| matches!(key, LuaMessage::String(_)))
+    {
+        LuaMessage::Table(
+            entries
+                .into_iter()
+                .filter_map(|(key, value)| match key {
+                    LuaMessage::String(key) => Some((key, value)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    } else {
+        LuaMessage::Map(
+            entries
+                .into_iter()
+                .filter_map(|(key, value)| Some((LuaKey::new(key)?, value)))
+                .collect(),
+        )
+    }
+}
+
+/// Converts the Lua value within the `ConversionOptions` of the VM.
+impl<'lua> FromLua<'lua> for LuaMessage {
+    fn from_lua(v: Value<'lua>, lua: Context<'lua>) -> LuaResult<LuaMessage> {
+        let from = lua_type_name(&v);
+        let mut converter = Converter {
+            lua,
+            options: conversion_options(lua)?,
+            depth: 0,
+            elements: 0,
+            visiting: None,
+        };
+        match converter.convert(v) {
+            Ok(message) => Ok(message),
+            Err(Stop::Lua(err)) => Err(err),
+            Err(Stop::Limit(message, path)) => Err(Error::FromLuaConversionError {
+                from,
+                to: "LuaMessage",
+                message: Some(match path.is_empty() {
+                    true => message,
+                    false => format!(
+                        "{} at {}",
+                        message,
+                        path.into_iter().rev().collect::<String>()
+                    ),
+                }),
+            }),
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for LuaMessage {
+    fn to_lua(self, lua: Context<'lua>) -> LuaResult<Value<'lua>> {
         match self {
             LuaMessage::String(x) => Ok(Value::String(lua.create_string(&x)?)),
+            LuaMessage::Bytes(x) => Ok(Value::String(lua.create_string(&x)?)),
             LuaMessage::Integer(x) => Ok(Value::Integer(x)),
             LuaMessage::Number(x) => Ok(Value::Number(x)),
             LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
             LuaMessage::Nil => Ok(Value::Nil),
             LuaMessage::Table(x) => Ok(Value::Table(lua.create_table_from(x)?)),
+            LuaMessage::Map(x) => Ok(Value::Table(
+                lua.create_table_from(x.into_iter().map(|(k, v)| (k.into_message(), v)))?,
+            )),
             LuaMessage::Array(x) => Ok(Value::Table(lua.create_sequence_from(x)?)),
+            LuaMessage::SparseArray(x) => Ok(Value::Table(lua.create_table_from(x)?)),
             LuaMessage::Variadic(x) => Ok(Value::Table(lua.create_sequence_from(x.0)?)),
+            LuaMessage::UserData(x) => Ok(Value::UserData((x.vtable.to_lua)(&x, lua)?)),
+            LuaMessage::Function(x) => Ok(Value::Function(x.function(lua)?)),
+            LuaMessage::Handle(x) => x.value(lua),
             // You should not create RPCNotifyLater from outside of lua
             // _ => unimplemented!(),
         }
@@ -432,89 +1727,123 @@ impl MultiLuaMessage {
             }
         }
     }
+
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            LuaMessage::Variadic(v) => v.0.len(),
+            _ => 1,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get the value at the index, or `Nil` (as missing Lua values).
+    pub fn get(&self, index: usize) -> LuaMessage {
+        match &self.0 {
+            LuaMessage::Variadic(v) => v.0.get(index).cloned().unwrap_or(LuaMessage::Nil),
+            _ if index == 0 => self.0.clone(),
+            _ => LuaMessage::Nil,
+        }
+    }
+}
+
+impl From<MultiLuaMessage> for Vec<LuaMessage> {
+    fn from(s: MultiLuaMessage) -> Self {
+        match s.0 {
+            LuaMessage::Variadic(v) => v.0.into(),
+            _ => vec![s.0],
+        }
+    }
+}
+
+impl<'lua> FromLuaMulti<'lua> for MultiLuaMessage {
+    fn from_lua_multi(values: MultiValue<'lua>, lua: Context<'lua>) -> LuaResult<Self> {
+        values
+            .into_iter()
+            .map(|v| LuaMessage::from_lua(v, lua))
+            .collect::<LuaResult<MultiLuaMessage>>()
+    }
 }
 
 impl<'lua> ToLuaMulti<'lua> for MultiLuaMessage {
     fn to_lua_multi(self, lua: Context<'lua>) -> LuaResult<MultiValue<'lua>> {
         match self.0 {
             LuaMessage::Variadic(x) => {
-                Ok(Variadic::<LuaMessage>::from_iter(x.0.into_iter()).to_lua_multi(lua)?)
+                Ok(Variadic::<LuaMessage>::from_iter(x.0).to_lua_multi(lua)?)
             }
             _ => Ok(self.0.to_lua_multi(lua)?),
         }
     }
 }
 
-impl Into<MultiLuaMessage> for LuaMessage {
-    fn into(self) -> MultiLuaMessage {
-        MultiLuaMessage { 0: self }
+impl From<LuaMessage> for MultiLuaMessage {
+    fn from(s: LuaMessage) -> Self {
+        MultiLuaMessage(s)
     }
 }
 
 impl From<Vec<LuaMessage>> for MultiLuaMessage {
     fn from(s: Vec<LuaMessage>) -> Self {
-        MultiLuaMessage {
-            0: LuaMessage::Variadic(VariadicLuaMessage {
-                0: VecDeque::<LuaMessage>::from(s),
-            }),
-        }
+        MultiLuaMessage(LuaMessage::Variadic(VariadicLuaMessage(VecDeque::<
+            LuaMessage,
+        >::from(s))))
     }
 }
 
 impl From<VecDeque<LuaMessage>> for MultiLuaMessage {
     fn from(s: VecDeque<LuaMessage>) -> Self {
-        MultiLuaMessage {
-            0: LuaMessage::Variadic(VariadicLuaMessage { 0: s }),
-        }
+        MultiLuaMessage(LuaMessage::Variadic(VariadicLuaMessage(s)))
     }
 }
 
 impl FromIterator<LuaMessage> for MultiLuaMessage {
     fn from_iter<I: IntoIterator<Item = LuaMessage>>(iter: I) -> Self {
-        MultiLuaMessage {
-            0: LuaMessage::Variadic(VariadicLuaMessage(VecDeque::<LuaMessage>::from_iter(iter))),
-        }
+        MultiLuaMessage(LuaMessage::Variadic(VariadicLuaMessage(VecDeque::<
+            LuaMessage,
+        >::from_iter(
+            iter
+        ))))
     }
 }
 
 impl From<Variadic<LuaMessage>> for MultiLuaMessage {
     fn from(s: Variadic<LuaMessage>) -> Self {
-        MultiLuaMessage {
-            0: LuaMessage::Variadic(VariadicLuaMessage(VecDeque::<LuaMessage>::from(s.to_vec()))),
-        }
+        MultiLuaMessage(LuaMessage::Variadic(VariadicLuaMessage(VecDeque::<
+            LuaMessage,
+        >::from(
+            s.to_vec()
+        ))))
     }
 }
 
 impl From<VariadicLuaMessage> for MultiLuaMessage {
     fn from(s: VariadicLuaMessage) -> Self {
-        MultiLuaMessage {
-            0: LuaMessage::Variadic(s),
-        }
+        MultiLuaMessage(LuaMessage::Variadic(s))
     }
 }
 
 macro_rules! impl_tuple {
     () => (
-        impl Into<MultiLuaMessage> for () {
-            fn into(self) -> MultiLuaMessage {
+        impl From<()> for MultiLuaMessage {
+            fn from(_: ()) -> MultiLuaMessage {
                 MultiLuaMessage::from_iter([])
             }
         }
-        impl Into<LuaMessage> for () {
-            fn into(self) -> LuaMessage {
+        impl From<()> for LuaMessage {
+            fn from(_: ()) -> LuaMessage {
                 LuaMessage::from_iter([])
             }
         }
     );
 
     ($last:ident $($name:ident)*) => (
-        impl<$($name,)* $last> Into<MultiLuaMessage> for ($($name,)* $last,)
+        impl<$($name,)* $last> From<($($name,)* $last,)> for MultiLuaMessage
             where $($name: Into<LuaMessage>,)*
                   $last: Into<MultiLuaMessage>
         {
             #[allow(non_snake_case)]
-            fn into(self) -> MultiLuaMessage {
-                let ($($name,)* $last,) = self;
+            fn from(s: ($($name,)* $last,)) -> MultiLuaMessage {
+                let ($($name,)* $last,) = s;
 
                 let mut v = MultiLuaMessage::from_iter([]);
                 let lastOne = $last.into().0;
@@ -692,9 +2021,495 @@ mod tests {
                 discriminant(&LuaMessage::Array(VecDeque::from(t)))
             );
 
+            let multi = lua
+                .load("return 1, nil, 'x'")
+                .eval::<MultiLuaMessage>()
+                .unwrap();
+            assert_eq!(3, multi.len());
+            assert_eq!(LuaMessage::Nil, multi.get(1));
+            assert_eq!(
+                vec![LuaMessage::from(1), LuaMessage::Nil, LuaMessage::from("x")],
+                Vec::<LuaMessage>::from(multi)
+            );
+            assert!(lua
+                .load("return")
+                .eval::<MultiLuaMessage>()
+                .unwrap()
+                .is_empty());
+
             // println!("{:?}\n{:?}", LuaMessage::Array(t.clone()), LuaMessage::from_lua(
             //     Value::Table(lua.create_sequence_from(vec!(LuaMessage::from(12),LuaMessage::from(2))).unwrap()), &lua
             // ).unwrap());
         })
     }
+
+    #[test]
+    fn display() {
+        let mut table = HashMap::new();
+        table.insert("name".to_string(), LuaMessage::from("demo"));
+        table.insert("list".to_string(), LuaMessage::from_slice(vec![1, 2]));
+        assert_eq!(
+            r#"{list = {1, 2}, name = "demo"}"#,
+            LuaMessage::from(table).to_string()
+        );
+        assert_eq!("demo", LuaMessage::from("demo").to_string());
+        assert_eq!("1.0", LuaMessage::from(1.0).to_string());
+        assert_eq!("nil", LuaMessage::Nil.to_string());
+    }
+
+    #[test]
+    fn userdata() {
+        use rlua::{Lua, UserDataMethods};
+
+        #[derive(Clone)]
+        struct Counter(i64);
+        impl UserData for Counter {
+            fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+                methods.add_method("get", |_, this, ()| Ok(this.0));
+                methods.add_method_mut("incr", |_, this, ()| {
+                    this.0 += 1;
+                    Ok(())
+                });
+            }
+        }
+
+        let lua_vm = Lua::new();
+        lua_vm.context(|lua| {
+            let counter = LuaUserData::new(Counter(1));
+            assert!(counter.is::<Counter>());
+            assert_eq!(counter, counter.clone());
+
+            let incr = lua
+                .load("function(c) c:incr() return c, c:get() end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            let (back, value) = incr
+                .call::<_, (LuaMessage, i64)>(LuaMessage::from(counter))
+                .unwrap();
+            assert_eq!(2, value);
+            let back = Option::<LuaUserData>::from(back).unwrap();
+            assert_eq!(2, back.downcast_ref::<Counter>().unwrap().0);
+
+            let plain = lua.create_userdata(Counter(0)).unwrap();
+            set_conversion_options(
+                lua,
+                ConversionOptions {
+                    opaque: OpaquePolicy::Error,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert!(LuaMessage::from_lua(Value::UserData(plain), lua).is_err());
+        })
+    }
+
+    #[test]
+    fn handles() {
+        use rlua::Lua;
+
+        let lua_vm = Lua::new();
+        lua_vm.context(|lua| {
+            let values = lua
+                .load(
+                    r#"
+                    co = coroutine.create(function() end)
+                    return {co, co, coroutine.create(function() end)}
+                "#,
+                )
+                .eval::<Vec<LuaMessage>>()
+                .unwrap();
+            let handle = Option::<LuaHandle>::from(values[0].clone()).unwrap();
+            assert_eq!("thread", handle.type_name());
+            assert_eq!(values[0], values[1]);
+            assert_ne!(values[0], values[2]);
+            assert_eq!(format!("thread: {}", handle.id()), values[0].to_string());
+
+            let same = lua
+                .load("function(t) return t == co end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            assert!(same.call::<_, bool>(values[0].clone()).unwrap());
+
+            let convert = |opaque| {
+                set_conversion_options(
+                    lua,
+                    ConversionOptions {
+                        opaque,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                lua.load("return {co}").eval::<LuaMessage>()
+            };
+            assert_eq!("{nil}", convert(OpaquePolicy::Nil).unwrap().to_string());
+            let err = convert(OpaquePolicy::Error).err().unwrap().to_string();
+            assert!(err.contains("threads are not converted at [1]"), "{}", err);
+        });
+
+        // The same ids in different VMs are different values.
+        let first = || {
+            Lua::new().context(|lua| {
+                lua.load("return coroutine.create(function() end)")
+                    .eval::<LuaMessage>()
+                    .unwrap()
+            })
+        };
+        let (a, b) = (first(), first());
+        assert_eq!(a.to_string(), b.to_string());
+        assert_ne!(a, b);
+        assert_ne!(LuaKey::new(a).unwrap(), LuaKey::new(b).unwrap());
+    }
+
+    #[test]
+    fn bytes() {
+        use rlua::Lua;
+
+        let lua_vm = Lua::new();
+        lua_vm.context(|lua| {
+            let packed = lua
+                .load(r#"return string.char(0, 255, 104, 105), "hi""#)
+                .eval::<(LuaMessage, LuaMessage)>()
+                .unwrap();
+            assert_eq!(LuaMessage::Bytes(vec![0, 255, 104, 105]), packed.0);
+            assert_eq!(LuaMessage::from("hi"), packed.1);
+            assert_eq!(r#"\x00\xffhi"#, packed.0.to_string());
+
+            let len = lua
+                .load("function(s) return #s, s:byte(2) end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            assert_eq!(
+                (4, 255),
+                len.call::<_, (i64, i64)>(packed.0.clone()).unwrap()
+            );
+            let back = lua
+                .load("function(s) return s end")
+                .eval::<rlua::Function>()
+                .unwrap()
+                .call::<_, LuaMessage>(LuaMessage::from(&[1u8, 200][..]))
+                .unwrap();
+            assert_eq!(LuaMessage::from(vec![1u8, 200]), back);
+        });
+
+        assert_eq!(
+            Some(vec![104, 105]),
+            Option::<Vec<u8>>::from(LuaMessage::from("hi"))
+        );
+        assert_eq!(
+            Some("hi".to_string()),
+            Option::<String>::from(LuaMessage::Bytes(b"hi".to_vec()))
+        );
+        assert_eq!(None, Option::<String>::from(LuaMessage::Bytes(vec![255])));
+        assert_eq!(
+            Some(vec![0, 255]),
+            Vec::<u8>::from_lua_message(LuaMessage::Bytes(vec![0, 255]))
+        );
+    }
+
+    #[test]
+    fn maps() {
+        use rlua::Lua;
+
+        let key = |k: LuaMessage| LuaKey::new(k).unwrap();
+        assert_eq!(key(LuaMessage::from(1)), key(LuaMessage::from(1.0)));
+        assert_eq!(key(LuaMessage::from("a")), key(LuaMessage::from(&b"a"[..])));
+        assert!(LuaKey::new(LuaMessage::Nil).is_none());
+        assert!(LuaKey::new(f64::NAN).is_none());
+        assert!(LuaKey::new(LuaMessage::Table(HashMap::new())).is_none());
+        assert_eq!(key(LuaMessage::from(-0.0)), key(LuaMessage::from(0)));
+
+        let lua_vm = Lua::new();
+        lua_vm.context(|lua| {
+            let sparse = lua
+                .load(r#"return {[10] = "ten", [true] = "yes", [1.5] = "half", name = "x"}"#)
+                .eval::<LuaMessage>()
+                .unwrap();
+            assert_eq!(
+                r#"{[1.5] = "half", [10] = "ten", name = "x", [true] = "yes"}"#,
+                sparse.to_string()
+            );
+            let map = Option::<HashMap<LuaKey, LuaMessage>>::from(sparse.clone()).unwrap();
+            assert_eq!(LuaMessage::from("ten"), map[&key(LuaMessage::from(10))]);
+            assert_eq!(LuaMessage::from("yes"), map[&key(LuaMessage::from(true))]);
+
+            let check = lua
+                .load(
+                    r#"function(t)
+                        return t[10] == "ten" and t[true] == "yes" and t[1.5] == "half"
+                            and t["10"] == nil and t.name == "x"
+                    end"#,
+                )
+                .eval::<rlua::Function>()
+                .unwrap();
+            assert!(check.call::<_, bool>(sparse.clone()).unwrap());
+            let back = lua
+                .load("function(t) return t end")
+                .eval::<rlua::Function>()
+                .unwrap()
+                .call::<_, LuaMessage>(sparse.clone())
+                .unwrap();
+            assert_eq!(sparse, back);
+
+            // Tables with string keys only stay `Table`.
+            match lua.load("return {a = 1}").eval::<LuaMessage>().unwrap() {
+                LuaMessage::Table(_) => {}
+                message => panic!("not a table: {:?}", message),
+            }
+
+            // Tables, functions & userdata are keys by identity.
+            let keyed = lua
+                .load("k = {} return {[k] = 1, [{}] = 2, [{}] = 3, [print] = 4}")
+                .eval::<LuaMessage>()
+                .unwrap();
+            let map = Option::<HashMap<LuaKey, LuaMessage>>::from(keyed.clone()).unwrap();
+            assert_eq!(4, map.len());
+            let check = lua
+                .load("function(t) return t[k] == 1 and t[print] == 4 end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            assert!(check.call::<_, bool>(keyed).unwrap());
+        });
+    }
+
+    #[test]
+    fn arrays() {
+        use rlua::Lua;
+
+        let lua_vm = Lua::new();
+        lua_vm.context(|lua| {
+            let eval = |source: &str| lua.load(source).eval::<LuaMessage>().unwrap();
+            assert_eq!(
+                LuaMessage::from_slice(vec![
+                    LuaMessage::from(1),
+                    LuaMessage::Nil,
+                    LuaMessage::from(3)
+                ]),
+                eval("return {1, nil, 3}")
+            );
+            assert_eq!("{nil, 2}", eval("return {[2] = 2}").to_string());
+            let sparse = eval("return {[1] = 'a', [1000] = 'b'}");
+            assert_eq!(r#"{[1] = "a", [1000] = "b"}"#, sparse.to_string());
+            assert_eq!(
+                Some(BTreeMap::from_iter([
+                    (1, LuaMessage::from("a")),
+                    (1000, LuaMessage::from("b"))
+                ])),
+                Option::<BTreeMap<i64, LuaMessage>>::from(sparse.clone())
+            );
+            let back = lua
+                .load("function(t) return t end")
+                .eval::<rlua::Function>()
+                .unwrap()
+                .call::<_, LuaMessage>(sparse.clone())
+                .unwrap();
+            assert_eq!(sparse, back);
+            // Not positive integers.
+            match eval("return {[0] = 1, [1] = 2}") {
+                LuaMessage::Map(_) => {}
+                message => panic!("not a map: {:?}", message),
+            }
+            assert_eq!(LuaMessage::Table(HashMap::new()), eval("return {}"));
+
+            set_conversion_options(
+                lua,
+                ConversionOptions {
+                    max_hole_ratio: 0.0,
+                    empty_table_as_array: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!("{[1] = 1, [3] = 3}", eval("return {1, nil, 3}").to_string());
+            assert_eq!(LuaMessage::Array(VecDeque::new()), eval("return {}"));
+
+            // Ratios of 1 or more would fill arrays with holes unbounded by their entries.
+            for ratio in &[1.0, 2.0, -0.5, f64::NAN] {
+                let options = ConversionOptions {
+                    max_hole_ratio: *ratio,
+                    ..Default::default()
+                };
+                assert!(set_conversion_options(lua, options).is_err());
+            }
+            set_conversion_options(lua, ConversionOptions::default()).unwrap();
+
+            // Sparse arrays are arrays with holes.
+            let items = vec![None, None, Some(1)];
+            let message = LuaMessage::from_slice(items.iter().map(|item| match item {
+                Some(item) => LuaMessage::from(*item),
+                None => LuaMessage::Nil,
+            }));
+            let back = lua
+                .load("function(t) return t end")
+                .eval::<rlua::Function>()
+                .unwrap()
+                .call::<_, LuaMessage>(message)
+                .unwrap();
+            assert_eq!(
+                LuaMessage::SparseArray(BTreeMap::from_iter([(3, LuaMessage::from(1))])),
+                back
+            );
+            assert_eq!(
+                Some(items),
+                Vec::<Option<i32>>::from_lua_message(back.clone())
+            );
+            assert_eq!(None, Vec::<i32>::from_lua_message(back.clone()));
+            assert_eq!(
+                Ok(vec![LuaMessage::Nil, LuaMessage::Nil, LuaMessage::from(1)]),
+                Vec::<LuaMessage>::try_from(back)
+            );
+            let far =
+                LuaMessage::SparseArray(BTreeMap::from_iter([(1 << 40, LuaMessage::from(1))]));
+            assert_eq!(None, Vec::<Option<i32>>::from_lua_message(far));
+        });
+    }
+
+    #[test]
+    fn try_from() {
+        assert_eq!(Ok(true), bool::try_from(LuaMessage::from(true)));
+        assert_eq!(
+            Err(ConversionError {
+                expected: "bool",
+                found: "number"
+            }),
+            bool::try_from(LuaMessage::from(1))
+        );
+        assert_eq!(Ok(3), i32::try_from(LuaMessage::from(3.0)));
+        assert_eq!(
+            "expected u8, got number",
+            u8::try_from(LuaMessage::from(300)).unwrap_err().to_string()
+        );
+        assert!(i64::try_from(LuaMessage::from(1.5)).is_err());
+        // Beyond `i64::MAX`, `u64`s are floats, with `u64::MAX` round-tripping.
+        let max = LuaMessage::from(u64::MAX);
+        assert_eq!(LuaMessage::Number(u64::MAX as f64), max);
+        assert_eq!(Ok(u64::MAX), u64::try_from(max.clone()));
+        assert_eq!(Some(u64::MAX), Option::<u64>::from(max.clone()));
+        assert_eq!(Some(u64::MAX), u64::from_lua_message(max));
+        assert_eq!(
+            LuaMessage::Integer(i64::MAX),
+            LuaMessage::from(i64::MAX as u64)
+        );
+        assert!(u64::try_from(LuaMessage::from(-1)).is_err());
+        assert!(u64::try_from(LuaMessage::from(1e30)).is_err());
+        assert!(i64::try_from(LuaMessage::from("1")).is_err());
+        assert_eq!(Ok(2.0), f64::try_from(LuaMessage::from(2)));
+        assert_eq!(Ok(0.5), f32::try_from(LuaMessage::from(0.5)));
+        assert_eq!(Ok(16777216.0), f32::try_from(LuaMessage::from(1 << 24)));
+        assert_eq!(
+            Err(ConversionError {
+                expected: "f32",
+                found: "number"
+            }),
+            f32::try_from(LuaMessage::from(1e300))
+        );
+        assert!(f32::try_from(LuaMessage::from(0.1)).is_err());
+        assert!(f32::try_from(LuaMessage::from((1 << 24) + 1)).is_err());
+        assert!(f64::try_from(LuaMessage::from((1_i64 << 53) + 1)).is_err());
+        assert!(f64::try_from(LuaMessage::from(i64::MAX)).is_err());
+        assert_eq!(
+            Ok(-9.223372036854776e18),
+            f64::try_from(LuaMessage::from(i64::MIN))
+        );
+        assert_eq!(Ok("x".to_string()), String::try_from(LuaMessage::from("x")));
+        assert!(String::try_from(LuaMessage::from(1)).is_err());
+        assert_eq!(
+            Err(ConversionError {
+                expected: "String",
+                found: "string"
+            }),
+            String::try_from(LuaMessage::Bytes(vec![255]))
+        );
+        assert_eq!(
+            Ok(vec![LuaMessage::from(1)]),
+            Vec::<LuaMessage>::try_from(LuaMessage::from_slice(vec![1]))
+        );
+        assert_eq!(
+            Err(ConversionError {
+                expected: "HashMap<String, LuaMessage>",
+                found: "array"
+            }),
+            HashMap::<String, LuaMessage>::try_from(LuaMessage::from_slice(vec![1]))
+        );
+        assert_eq!(
+            Ok(BTreeMap::from_iter([(1, LuaMessage::from(1))])),
+            BTreeMap::<i64, LuaMessage>::try_from(LuaMessage::from_slice(vec![1]))
+        );
+
+        // The lenient conversions are opt-in.
+        assert_eq!(Ok(true), LuaMessage::from(1).coerce::<bool>());
+        assert_eq!(Ok(1), LuaMessage::from("1").coerce::<i64>());
+        assert_eq!(
+            Err(ConversionError {
+                expected: "i64",
+                found: "nil"
+            }),
+            LuaMessage::Nil.coerce::<i64>()
+        );
+        // The same names as the strict conversions.
+        assert_eq!(
+            Err(ConversionError {
+                expected: "String",
+                found: "nil"
+            }),
+            LuaMessage::Nil.coerce::<String>()
+        );
+        assert_eq!(
+            "expected HashMap<String, LuaMessage>, got nil",
+            LuaMessage::Nil
+                .coerce::<HashMap<String, LuaMessage>>()
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        extern crate serde_json;
+
+        let mut table = HashMap::new();
+        table.insert("name".to_string(), LuaMessage::from("demo"));
+        table.insert("ratio".to_string(), LuaMessage::from(1.0));
+        table.insert("list".to_string(), LuaMessage::from_slice(vec![1, 2]));
+        table.insert("none".to_string(), LuaMessage::Nil);
+        let table = LuaMessage::from(table);
+
+        let json = serde_json::to_string(&table).unwrap();
+        assert_eq!(table, serde_json::from_str::<LuaMessage>(&json).unwrap());
+        assert_eq!(
+            serde_json::json!({"name": "demo", "ratio": 1.0, "list": [1, 2], "none": null}),
+            serde_json::to_value(&table).unwrap()
+        );
+        assert_eq!(
+            LuaMessage::Number(1.0),
+            serde_json::from_str::<LuaMessage>("1.0").unwrap()
+        );
+        assert_eq!(
+            LuaMessage::Number(u64::MAX as f64),
+            serde_json::from_str::<LuaMessage>(&u64::MAX.to_string()).unwrap()
+        );
+
+        let multi = MultiLuaMessage::from(vec![LuaMessage::from(1), LuaMessage::Nil]);
+        assert_eq!("[1,null]", serde_json::to_string(&multi).unwrap());
+        assert_eq!(multi, serde_json::from_str("[1,null]").unwrap());
+        assert_eq!(
+            "[3]",
+            serde_json::to_string(&MultiLuaMessage::from(LuaMessage::from(3))).unwrap()
+        );
+
+        let map = LuaMessage::from(HashMap::from_iter([(
+            LuaKey::new(10).unwrap(),
+            LuaMessage::from("ten"),
+        )]));
+        assert_eq!(r#"{"10":"ten"}"#, serde_json::to_string(&map).unwrap());
+        assert_eq!(
+            HashMap::from_iter([(10, "ten".to_string())]),
+            from_message::<HashMap<i64, String>>(map).unwrap()
+        );
+
+        #[derive(Clone)]
+        struct Opaque;
+        impl UserData for Opaque {}
+        assert!(serde_json::to_string(&LuaMessage::from(LuaUserData::new(Opaque))).is_err());
+    }
 }
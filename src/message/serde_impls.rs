@@ -0,0 +1,1273 @@
+/*!
+The serde support of `LuaMessage` (feature `serde`, see `LuaMessage` for the mapping),
+and the conversions of any serde type to & from `LuaMessage`.
+*/
+
+use std::collections::{hash_map, vec_deque};
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+
+use super::*;
+use serde::de::{
+    self, Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde::ser::{
+    self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+    SerializeTuple, SerializeTupleStruct, SerializeTupleVariant, Serializer,
+};
+
+impl Serialize for LuaMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LuaMessage::String(s) => serializer.serialize_str(s),
+            LuaMessage::Bytes(b) => serializer.serialize_bytes(b),
+            LuaMessage::Integer(i) => serializer.serialize_i64(*i),
+            LuaMessage::Number(n) => serializer.serialize_f64(*n),
+            LuaMessage::Boolean(b) => serializer.serialize_bool(*b),
+            LuaMessage::Nil => serializer.serialize_unit(),
+            LuaMessage::Table(table) => serializer.collect_map(table),
+            LuaMessage::Map(map) => {
+                serializer.collect_map(map.iter().map(|(k, v)| (k.message(), v)))
+            }
+            LuaMessage::Array(array) => serializer.collect_seq(array),
+            LuaMessage::SparseArray(array) => serializer.collect_map(array),
+            LuaMessage::Variadic(variadic) => variadic.serialize(serializer),
+            LuaMessage::UserData(userdata) => Err(ser::Error::custom(format!(
+                "cannot serialize the userdata {}",
+                userdata.type_name()
+            ))),
+            LuaMessage::Function(_) => Err(ser::Error::custom("cannot serialize a function")),
+            LuaMessage::Handle(handle) => Err(ser::Error::custom(format!(
+                "cannot serialize a {}",
+                handle.type_name()
+            ))),
+        }
+    }
+}
+
+impl Serialize for VariadicLuaMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&self.0)
+    }
+}
+
+impl Serialize for MultiLuaMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            LuaMessage::Variadic(ref variadic) => variadic.serialize(serializer),
+            ref value => {
+                let mut seq = serializer.serialize_seq(Some(1))?;
+                seq.serialize_element(value)?;
+                seq.end()
+            }
+        }
+    }
+}
+
+struct LuaMessageVisitor;
+
+impl<'de> Visitor<'de> for LuaMessageVisitor {
+    type Value = LuaMessage;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a Lua value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Boolean(v))
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Integer(v))
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<LuaMessage, E> {
+        Ok(match i64::try_from(v) {
+            Ok(i) => LuaMessage::Integer(i),
+            Err(_) => LuaMessage::Number(v as f64),
+        })
+    }
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Number(v))
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+    fn visit_string<E: de::Error>(self, v: String) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v))
+    }
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<LuaMessage, E> {
+        match std::str::from_utf8(v) {
+            Ok(s) => Ok(LuaMessage::String(s.to_string())),
+            Err(_) => Ok(LuaMessage::Bytes(v.to_vec())),
+        }
+    }
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<LuaMessage, E> {
+        match String::from_utf8(v) {
+            Ok(s) => Ok(LuaMessage::String(s)),
+            Err(err) => Ok(LuaMessage::Bytes(err.into_bytes())),
+        }
+    }
+    fn visit_unit<E: de::Error>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+    fn visit_none<E: de::Error>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<LuaMessage, D::Error> {
+        LuaMessage::deserialize(deserializer)
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LuaMessage, A::Error> {
+        let mut array = VecDeque::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? {
+            array.push_back(item);
+        }
+        Ok(LuaMessage::Array(array))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<LuaMessage, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry::<LuaMessage, LuaMessage>()? {
+            entries.push(entry);
+        }
+        if entries
+            .iter()
+            .all(|(key, _)| matches!(key, LuaMessage::String(_)))
+        {
+            return Ok(LuaMessage::Table(
+                entries
+                    .into_iter()
+                    .filter_map(|(key, value)| match key {
+                        LuaMessage::String(key) => Some((key, value)),
+                        _ => None,
+                    })
+                    .collect(),
+            ));
+        }
+        let mut table = HashMap::with_capacity(entries.len());
+        for (key, value) in entries {
+            let key = LuaKey::new(key).ok_or_else(|| de::Error::custom("nil or NaN key"))?;
+            table.insert(key, value);
+        }
+        Ok(LuaMessage::Map(table))
+    }
+}
+
+impl<'de> Deserialize<'de> for LuaMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LuaMessage, D::Error> {
+        deserializer.deserialize_any(LuaMessageVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for VariadicLuaMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VariadicLuaMessage, D::Error> {
+        Ok(VariadicLuaMessage(VecDeque::deserialize(deserializer)?))
+    }
+}
+
+impl<'de> Deserialize<'de> for MultiLuaMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MultiLuaMessage, D::Error> {
+        Ok(MultiLuaMessage::from(VariadicLuaMessage::deserialize(
+            deserializer,
+        )?))
+    }
+}
+
+/// Convert a serde type to `LuaMessage` (see `LuaMessage` for the mapping).
+///
+/// Structs & maps become tables, sequences & tuples become arrays,
+/// unit variants become their names & other variants `{variant = value}` tables.
+pub fn to_message<T: Serialize + ?Sized>(value: &T) -> Result<LuaMessage, MessageError> {
+    value.serialize(MessageSerializer::default())
+}
+
+/// Convert a `LuaMessage` to a serde type (the reverse of `to_message`).
+///
+/// Floats without fraction are accepted as integers, and the errors tell the path of the value.
+pub fn from_message<T: DeserializeOwned>(message: LuaMessage) -> Result<T, MessageError> {
+    T::deserialize(MessageDeserializer(message))
+}
+
+/// Convert a serde type to the values of a Lua call: tuples are many values, `()` is none.
+pub(crate) fn to_multi_message<T: Serialize + ?Sized>(
+    value: &T,
+) -> Result<MultiLuaMessage, MessageError> {
+    Ok(match value.serialize(MessageSerializer { multi: true })? {
+        LuaMessage::Variadic(values) => MultiLuaMessage::from(values),
+        value => MultiLuaMessage::from(value),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// An error of `to_message` or `from_message`, at the path of the value (e.g. `items[3].price`).
+///
+/// The indexes of the path are Lua ones (from 1).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageError {
+    // From the value to the root.
+    path: Vec<PathSegment>,
+    message: String,
+}
+
+impl MessageError {
+    fn new(message: impl Into<String>) -> MessageError {
+        MessageError {
+            path: vec![],
+            message: message.into(),
+        }
+    }
+    fn at_key(mut self, key: &str) -> MessageError {
+        self.path.push(PathSegment::Key(key.to_string()));
+        self
+    }
+    fn at_index(mut self, index: usize) -> MessageError {
+        self.path.push(PathSegment::Index(index));
+        self
+    }
+
+    /// The path of the value (empty for the root).
+    pub fn path(&self) -> String {
+        let mut path = String::new();
+        for segment in self.path.iter().rev() {
+            match segment {
+                PathSegment::Key(key) if path.is_empty() => path.push_str(key),
+                PathSegment::Key(key) => path.push_str(&format!(".{}", key)),
+                PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "field {:?}: {}", self.path(), self.message)
+        }
+    }
+}
+
+impl StdError for MessageError {}
+
+impl ser::Error for MessageError {
+    fn custom<T: fmt::Display>(msg: T) -> MessageError {
+        MessageError::new(msg.to_string())
+    }
+}
+
+impl de::Error for MessageError {
+    fn custom<T: fmt::Display>(msg: T) -> MessageError {
+        MessageError::new(msg.to_string())
+    }
+}
+
+#[derive(Default)]
+struct MessageSerializer {
+    // Tuples & `()` as the values of a call.
+    multi: bool,
+}
+
+impl Serializer for MessageSerializer {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeVariant<SerializeTable>;
+
+    fn serialize_bool(self, v: bool) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Boolean(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_i16(self, v: i16) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_i32(self, v: i32) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_i64(self, v: i64) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Integer(v))
+    }
+    fn serialize_u8(self, v: u8) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_u16(self, v: u16) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_u32(self, v: u32) -> Result<LuaMessage, MessageError> {
+        self.serialize_i64(i64::from(v))
+    }
+    fn serialize_u64(self, v: u64) -> Result<LuaMessage, MessageError> {
+        Ok(match i64::try_from(v) {
+            Ok(i) => LuaMessage::Integer(i),
+            Err(_) => LuaMessage::Number(v as f64),
+        })
+    }
+    fn serialize_f32(self, v: f32) -> Result<LuaMessage, MessageError> {
+        self.serialize_f64(f64::from(v))
+    }
+    fn serialize_f64(self, v: f64) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Number(v))
+    }
+    fn serialize_char(self, v: char) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<LuaMessage, MessageError> {
+        match std::str::from_utf8(v) {
+            Ok(s) => self.serialize_str(s),
+            Err(_) => Ok(LuaMessage::Bytes(v.to_vec())),
+        }
+    }
+    fn serialize_none(self) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Nil)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<LuaMessage, MessageError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<LuaMessage, MessageError> {
+        Ok(match self.multi {
+            true => LuaMessage::Variadic(VariadicLuaMessage(VecDeque::new())),
+            false => LuaMessage::Nil,
+        })
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Nil)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<LuaMessage, MessageError> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<LuaMessage, MessageError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<LuaMessage, MessageError> {
+        let value = to_message(value).map_err(|err| err.at_key(variant))?;
+        Ok(variant_table(variant, value))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, MessageError> {
+        Ok(SerializeArray {
+            items: VecDeque::with_capacity(len.unwrap_or(0)),
+            multi: false,
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, MessageError> {
+        Ok(SerializeArray {
+            items: VecDeque::with_capacity(len),
+            multi: self.multi,
+        })
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, MessageError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeArray>, MessageError> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable, MessageError> {
+        Ok(SerializeTable {
+            table: HashMap::with_capacity(len.unwrap_or(0)),
+            others: HashMap::new(),
+            key: None,
+        })
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable, MessageError> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeTable>, MessageError> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SerializeArray {
+    items: VecDeque<LuaMessage>,
+    multi: bool,
+}
+
+impl SerializeSeq for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        let value = to_message(value).map_err(|err| err.at_index(self.items.len() + 1))?;
+        self.items.push_back(value);
+        Ok(())
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        Ok(match self.multi {
+            true => LuaMessage::Variadic(VariadicLuaMessage(self.items)),
+            false => LuaMessage::Array(self.items),
+        })
+    }
+}
+
+impl SerializeTuple for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl SerializeTupleStruct for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        SerializeSeq::end(self)
+    }
+}
+
+// A `Table`, or a `Map` once a key is an integer or a boolean.
+struct SerializeTable {
+    table: HashMap<String, LuaMessage>,
+    others: HashMap<LuaKey, LuaMessage>,
+    key: Option<LuaKey>,
+}
+
+impl SerializeMap for SerializeTable {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), MessageError> {
+        self.key = match to_message(key)? {
+            key @ LuaMessage::String(_)
+            | key @ LuaMessage::Integer(_)
+            | key @ LuaMessage::Boolean(_) => LuaKey::new(key),
+            key => {
+                return Err(MessageError::new(format!(
+                    "expected a string, integer or boolean key, got {}",
+                    key.type_name()
+                )))
+            }
+        };
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| MessageError::new("serialize_value called before serialize_key"))?;
+        match key.0 {
+            Key::String(key) => {
+                let value = to_message(value).map_err(|err| err.at_key(&key))?;
+                self.table.insert(key, value);
+            }
+            _ => {
+                let path = key.message().to_string();
+                let value = to_message(value).map_err(|err| err.at_key(&path))?;
+                self.others.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        if self.others.is_empty() {
+            return Ok(LuaMessage::Table(self.table));
+        }
+        let mut map = self.others;
+        map.extend(
+            self.table
+                .into_iter()
+                .map(|(key, value)| (LuaKey(Key::String(key)), value)),
+        );
+        Ok(LuaMessage::Map(map))
+    }
+}
+
+impl SerializeStruct for SerializeTable {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        let value = to_message(value).map_err(|err| err.at_key(key))?;
+        self.table.insert(key.to_string(), value);
+        Ok(())
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        Ok(LuaMessage::Table(self.table))
+    }
+}
+
+// `{variant = value}` tables.
+struct SerializeVariant<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+fn variant_table(variant: &str, value: LuaMessage) -> LuaMessage {
+    LuaMessage::Table(HashMap::from_iter([(variant.to_string(), value)]))
+}
+
+impl SerializeTupleVariant for SerializeVariant<SerializeArray> {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        let variant = self.variant;
+        SerializeSeq::serialize_element(&mut self.inner, value).map_err(|err| err.at_key(variant))
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        Ok(variant_table(self.variant, SerializeSeq::end(self.inner)?))
+    }
+}
+
+impl SerializeStructVariant for SerializeVariant<SerializeTable> {
+    type Ok = LuaMessage;
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        let variant = self.variant;
+        SerializeStruct::serialize_field(&mut self.inner, key, value)
+            .map_err(|err| err.at_key(variant))
+    }
+    fn end(self) -> Result<LuaMessage, MessageError> {
+        Ok(variant_table(
+            self.variant,
+            SerializeStruct::end(self.inner)?,
+        ))
+    }
+}
+
+struct MessageDeserializer(LuaMessage);
+
+impl MessageDeserializer {
+    fn unexpected(&self, expected: &str) -> MessageError {
+        MessageError::new(format!("expected {}, got {}", expected, self.0.type_name()))
+    }
+
+    fn deserialize_integer<'de, V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        // The floats beyond the 64-bit integers (or infinite) don't fit any target,
+        // but 2^64 is `u64::MAX` (see `From<u64>`).
+        match self.0 {
+            LuaMessage::Integer(i) => visitor.visit_i64(i),
+            LuaMessage::Number(n) if n.is_finite() && n.fract() != 0.0 => {
+                Err(MessageError::new("expected integer, got float"))
+            }
+            LuaMessage::Number(n) if (0.0..=U64_END).contains(&n) => visitor.visit_u64(n as u64),
+            LuaMessage::Number(n) if (i64::MIN as f64..0.0).contains(&n) => {
+                visitor.visit_i64(n as i64)
+            }
+            _ => Err(self.unexpected("integer")),
+        }
+    }
+    fn deserialize_float<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Integer(i) => visitor.visit_f64(i as f64),
+            LuaMessage::Number(n) => visitor.visit_f64(n),
+            _ => Err(self.unexpected("number")),
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for MessageDeserializer {
+    type Error = MessageError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::String(s) => visitor.visit_string(s),
+            LuaMessage::Bytes(b) => visitor.visit_byte_buf(b),
+            LuaMessage::Integer(i) => visitor.visit_i64(i),
+            LuaMessage::Number(n) => visitor.visit_f64(n),
+            LuaMessage::Boolean(b) => visitor.visit_bool(b),
+            LuaMessage::Nil => visitor.visit_unit(),
+            LuaMessage::Table(table) => visitor.visit_map(TableAccess::new(table)),
+            LuaMessage::Map(map) => visitor.visit_map(MapEntries::new(map)),
+            LuaMessage::SparseArray(array) => visitor.visit_map(MapEntries::sparse(array)),
+            LuaMessage::Array(array) => visitor.visit_seq(ArrayAccess::new(array)),
+            LuaMessage::Variadic(values) => visitor.visit_seq(ArrayAccess::new(values.0)),
+            _ => Err(self.unexpected("a serializable value")),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Boolean(b) => visitor.visit_bool(b),
+            _ => Err(self.unexpected("boolean")),
+        }
+    }
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_integer(visitor)
+    }
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_float(visitor)
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_float(visitor)
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::String(s) => visitor.visit_string(s),
+            _ => Err(self.unexpected("string")),
+        }
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_byte_buf(visitor)
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::String(s) => visitor.visit_byte_buf(s.into_bytes()),
+            LuaMessage::Bytes(b) => visitor.visit_byte_buf(b),
+            LuaMessage::Array(array) => visitor.visit_seq(ArrayAccess::new(array)),
+            _ => Err(self.unexpected("string")),
+        }
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Nil => visitor.visit_unit(),
+            _ => Err(self.unexpected("nil")),
+        }
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        self.deserialize_unit(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Array(array) => visitor.visit_seq(ArrayAccess::new(array)),
+            LuaMessage::Variadic(values) => visitor.visit_seq(ArrayAccess::new(values.0)),
+            // Empty Lua tables come as `Table`.
+            LuaMessage::Table(ref table) if table.is_empty() => {
+                visitor.visit_seq(ArrayAccess::new(VecDeque::new()))
+            }
+            _ => Err(self.unexpected("array")),
+        }
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Table(table) => visitor.visit_map(TableAccess::new(table)),
+            LuaMessage::Map(map) => visitor.visit_map(MapEntries::new(map)),
+            LuaMessage::SparseArray(array) => visitor.visit_map(MapEntries::sparse(array)),
+            // Tables keyed by 1..n, e.g. `HashMap<i64, _>`.
+            array @ LuaMessage::Array(_) => {
+                visitor.visit_map(MapEntries::new(Option::from(array).unwrap_or_default()))
+            }
+            _ => Err(self.unexpected("table")),
+        }
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::Array(array) => visitor.visit_seq(ArrayAccess::new(array)),
+            _ => self.deserialize_map(visitor),
+        }
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        match self.0 {
+            LuaMessage::String(variant) => visitor.visit_enum(EnumDeserializer {
+                variant,
+                value: LuaMessage::Nil,
+            }),
+            LuaMessage::Table(ref table) if table.len() == 1 => {
+                let (variant, value) = table.clone().into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            _ => Err(self.unexpected("variant name or {variant = value} table")),
+        }
+    }
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_unit()
+    }
+}
+
+struct ArrayAccess {
+    items: vec_deque::IntoIter<LuaMessage>,
+    index: usize,
+}
+
+impl ArrayAccess {
+    fn new(items: VecDeque<LuaMessage>) -> ArrayAccess {
+        ArrayAccess {
+            items: items.into_iter(),
+            index: 0,
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for ArrayAccess {
+    type Error = MessageError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, MessageError> {
+        match self.items.next() {
+            Some(item) => {
+                self.index += 1;
+                let index = self.index;
+                seed.deserialize(MessageDeserializer(item))
+                    .map(Some)
+                    .map_err(|err| err.at_index(index))
+            }
+            None => Ok(None),
+        }
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct TableAccess {
+    entries: hash_map::IntoIter<String, LuaMessage>,
+    value: Option<(String, LuaMessage)>,
+}
+
+impl TableAccess {
+    fn new(table: HashMap<String, LuaMessage>) -> TableAccess {
+        TableAccess {
+            entries: table.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for TableAccess {
+    type Error = MessageError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, MessageError> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                let deserialized = seed
+                    .deserialize(KeyDeserializer(key.clone()))
+                    .map_err(|err| err.at_key(&key))?;
+                self.value = Some((key, value));
+                Ok(Some(deserialized))
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, MessageError> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| MessageError::new("next_value called before next_key"))?;
+        seed.deserialize(MessageDeserializer(value))
+            .map_err(|err| err.at_key(&key))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+// The keys of `Map` are deserialized as the values.
+struct MapEntries {
+    entries: hash_map::IntoIter<LuaKey, LuaMessage>,
+    value: Option<(String, LuaMessage)>,
+}
+
+impl MapEntries {
+    fn new(map: HashMap<LuaKey, LuaMessage>) -> MapEntries {
+        MapEntries {
+            entries: map.into_iter(),
+            value: None,
+        }
+    }
+    fn sparse(array: BTreeMap<i64, LuaMessage>) -> MapEntries {
+        MapEntries::new(Option::from(LuaMessage::SparseArray(array)).unwrap_or_default())
+    }
+}
+
+impl<'de> MapAccess<'de> for MapEntries {
+    type Error = MessageError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, MessageError> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                let path = key.message().to_string();
+                let deserialized = seed
+                    .deserialize(MessageDeserializer(key.into_message()))
+                    .map_err(|err| err.at_key(&path))?;
+                self.value = Some((path, value));
+                Ok(Some(deserialized))
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, MessageError> {
+        let (path, value) = self
+            .value
+            .take()
+            .ok_or_else(|| MessageError::new("next_value called before next_key"))?;
+        seed.deserialize(MessageDeserializer(value))
+            .map_err(|err| err.at_key(&path))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+// Table keys are strings, parsed when numbers or booleans are expected.
+struct KeyDeserializer(String);
+
+impl KeyDeserializer {
+    fn parse<T: std::str::FromStr>(&self, expected: &str) -> Result<T, MessageError> {
+        self.0
+            .parse()
+            .map_err(|_| MessageError::new(format!("expected {} key, got {:?}", expected, self.0)))
+    }
+}
+
+impl<'de> Deserializer<'de> for KeyDeserializer {
+    type Error = MessageError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_string(self.0)
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_bool(self.parse("boolean")?)
+    }
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i64(self.parse("integer")?)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i64(self.parse("integer")?)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i64(self.parse("integer")?)
+    }
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i64(self.parse("integer")?)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u64(self.parse("integer")?)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u64(self.parse("integer")?)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u64(self.parse("integer")?)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u64(self.parse("integer")?)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_enum(EnumDeserializer {
+            variant: self.0,
+            value: LuaMessage::Nil,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        f32 f64 char str string bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: LuaMessage,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = MessageError;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantDeserializer), MessageError> {
+        let variant = seed.deserialize(KeyDeserializer(self.variant.clone()))?;
+        Ok((
+            variant,
+            VariantDeserializer {
+                variant: self.variant,
+                value: self.value,
+            },
+        ))
+    }
+}
+
+struct VariantDeserializer {
+    variant: String,
+    value: LuaMessage,
+}
+
+impl<'de> VariantAccess<'de> for VariantDeserializer {
+    type Error = MessageError;
+
+    fn unit_variant(self) -> Result<(), MessageError> {
+        match self.value {
+            LuaMessage::Nil => Ok(()),
+            value => Err(MessageDeserializer(value)
+                .unexpected("nil")
+                .at_key(&self.variant)),
+        }
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, MessageError> {
+        let VariantDeserializer { variant, value } = self;
+        seed.deserialize(MessageDeserializer(value))
+            .map_err(|err| err.at_key(&variant))
+    }
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        let VariantDeserializer { variant, value } = self;
+        MessageDeserializer(value)
+            .deserialize_seq(visitor)
+            .map_err(|err| err.at_key(&variant))
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        let VariantDeserializer { variant, value } = self;
+        MessageDeserializer(value)
+            .deserialize_map(visitor)
+            .map_err(|err| err.at_key(&variant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_derive;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+    struct Item {
+        name: String,
+        price: f64,
+        tags: Vec<String>,
+        note: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+    enum Status {
+        Open,
+        Paid(i64),
+        Shipped { carrier: String },
+    }
+
+    #[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+    struct Order {
+        id: u32,
+        items: Vec<Item>,
+        status: Status,
+        counts: HashMap<u8, bool>,
+    }
+
+    fn item(name: &str, price: f64) -> Item {
+        Item {
+            name: name.to_string(),
+            price,
+            tags: vec![],
+            note: None,
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let order = Order {
+            id: 7,
+            items: vec![item("a", 1.5), item("b", 2.0)],
+            status: Status::Shipped {
+                carrier: "post".to_string(),
+            },
+            counts: HashMap::from_iter([(1, true)]),
+        };
+        let message = to_message(&order).unwrap();
+        match message {
+            LuaMessage::Table(ref table) => {
+                assert_eq!(Some(&LuaMessage::Integer(7)), table.get("id"));
+                assert_eq!(
+                    "{Shipped = {carrier = \"post\"}}",
+                    table["status"].to_string()
+                );
+                assert_eq!(
+                    LuaMessage::Map(HashMap::from_iter([(
+                        LuaKey::new(1).unwrap(),
+                        LuaMessage::from(true)
+                    )])),
+                    table["counts"]
+                );
+            }
+            _ => panic!("not a table: {:?}", message),
+        }
+        assert_eq!(order, from_message(message).unwrap());
+
+        // Mixed keys make a `Map`, and integer-keyed Lua arrays are maps too.
+        let mixed = LuaMessage::Map(HashMap::from_iter([
+            (LuaKey::new("name").unwrap(), LuaMessage::from("x")),
+            (LuaKey::new(2).unwrap(), LuaMessage::from(true)),
+        ]));
+        assert_eq!(mixed, to_message(&mixed).unwrap());
+        assert_eq!(
+            HashMap::from_iter([(1, "a".to_string()), (2, "b".to_string())]),
+            from_message::<HashMap<i64, String>>(LuaMessage::from_slice(vec!["a", "b"])).unwrap()
+        );
+
+        assert_eq!(LuaMessage::from("Open"), to_message(&Status::Open).unwrap());
+        assert_eq!(
+            Status::Paid(3),
+            from_message(LuaMessage::from(HashMap::from_iter([(
+                "Paid".to_string(),
+                LuaMessage::Number(3.0)
+            )])))
+            .unwrap()
+        );
+        // Empty Lua tables are empty arrays too.
+        assert_eq!(
+            Vec::<i64>::new(),
+            from_message::<Vec<i64>>(LuaMessage::Table(HashMap::new())).unwrap()
+        );
+        // Bytes which aren't UTF-8 stay bytes.
+        let bytes = LuaMessage::Bytes(vec![0, 255]);
+        assert_eq!(bytes, to_message(&bytes).unwrap());
+        assert_eq!(bytes, from_message::<LuaMessage>(bytes.clone()).unwrap());
+
+        assert_eq!(
+            MultiLuaMessage::from(vec![LuaMessage::from(1), LuaMessage::from("x")]),
+            to_multi_message(&(1, "x")).unwrap()
+        );
+        assert_eq!(0, to_multi_message(&()).unwrap().len());
+        assert_eq!(
+            MultiLuaMessage::from(LuaMessage::from_slice(vec![1, 2])),
+            to_multi_message(&vec![1, 2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn error_paths() {
+        let mut message = to_message(&Order {
+            id: 1,
+            items: vec![item("a", 1.0), item("b", 2.0), item("c", 3.0)],
+            status: Status::Open,
+            counts: HashMap::new(),
+        })
+        .unwrap();
+        if let LuaMessage::Table(ref mut table) = message {
+            if let Some(LuaMessage::Array(ref mut items)) = table.get_mut("items") {
+                if let LuaMessage::Table(ref mut item) = items[2] {
+                    item.insert("price".to_string(), LuaMessage::from("cheap"));
+                }
+            }
+        }
+        let err = from_message::<Order>(message).unwrap_err();
+        assert_eq!("items[3].price", err.path());
+        assert_eq!(
+            "field \"items[3].price\": expected number, got string",
+            err.to_string()
+        );
+
+        let err = from_message::<Vec<u8>>(LuaMessage::from_slice(vec![1.0, 2.5])).unwrap_err();
+        assert_eq!(
+            "field \"[2]\": expected integer, got float",
+            err.to_string()
+        );
+        // Integral floats must fit the target.
+        assert_eq!(3_u8, from_message::<u8>(LuaMessage::Number(3.0)).unwrap());
+        assert_eq!(-2_i8, from_message::<i8>(LuaMessage::Number(-2.0)).unwrap());
+        for (n, message) in &[
+            (1e30, "expected integer, got number"),
+            (f64::INFINITY, "expected integer, got number"),
+            (f64::NAN, "expected integer, got number"),
+        ] {
+            let err = from_message::<Vec<u64>>(LuaMessage::from_slice(vec![*n])).unwrap_err();
+            assert_eq!(format!("field \"[1]\": {}", message), err.to_string());
+        }
+        assert!(from_message::<u64>(LuaMessage::Number(-1.0)).is_err());
+        assert!(from_message::<i64>(LuaMessage::Number(-1e30)).is_err());
+        assert!(from_message::<u8>(LuaMessage::Number(256.0)).is_err());
+        let max = to_message(&u64::MAX).unwrap();
+        assert_eq!(LuaMessage::from(u64::MAX), max);
+        assert_eq!(u64::MAX, from_message::<u64>(max).unwrap());
+        let err = from_message::<HashMap<u8, bool>>(LuaMessage::from(HashMap::from_iter([(
+            "x".to_string(),
+            LuaMessage::from(true),
+        )])))
+        .unwrap_err();
+        assert_eq!(
+            "field \"x\": expected integer key, got \"x\"",
+            err.to_string()
+        );
+        assert_eq!(
+            "expected boolean, got nil",
+            from_message::<bool>(LuaMessage::Nil)
+                .unwrap_err()
+                .to_string()
+        );
+
+        let keys: HashMap<(i32, i32), i32> = HashMap::from_iter([((1, 2), 3)]);
+        let err = to_message(&keys).unwrap_err();
+        assert_eq!(
+            "expected a string, integer or boolean key, got array",
+            err.to_string()
+        );
+    }
+}
@@ -0,0 +1,264 @@
+/*!
+A sampling profiler of the Lua code run by an `Actor`.
+
+The stack is sampled every N VM instructions (see `Actor::start_profiler`),
+and nothing is hooked while the profiler is stopped.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use hook::{frame_info, HookEvent};
+use rlua::{Context, Error, Table};
+
+pub(crate) const HOOK_NAME: &str = "profiler";
+
+/// A function of the sampled stacks, by its name & where it's defined.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProfileFrame {
+    pub function: String,
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for ProfileFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}:{})", self.function, self.file, self.line)
+    }
+}
+
+/// The samples of a function: where it was running (self) or on the stack (total).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileEntry {
+    pub frame: ProfileFrame,
+    pub self_samples: u64,
+    pub total_samples: u64,
+}
+
+/// The sampled stacks (from the root to the running function) & their counts.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    stacks: HashMap<Vec<ProfileFrame>, u64>,
+}
+
+impl Profile {
+    pub fn stacks(&self) -> &HashMap<Vec<ProfileFrame>, u64> {
+        &self.stacks
+    }
+    pub fn samples(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// The folded stacks (`root;caller;function count` lines) of flamegraph tools.
+    pub fn folded(&self) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let frames = stack.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+                format!("{} {}", frames.join(";"), count)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    /// The `n` functions taking the most samples by themselves.
+    pub fn top(&self, n: usize) -> Vec<ProfileEntry> {
+        let mut entries = HashMap::<&ProfileFrame, (u64, u64)>::new();
+        for (stack, &count) in &self.stacks {
+            if let Some(frame) = stack.last() {
+                entries.entry(frame).or_default().0 += count;
+            }
+            // Recursive functions count once per stack.
+            for frame in stack.iter().collect::<HashSet<_>>() {
+                entries.entry(frame).or_default().1 += count;
+            }
+        }
+
+        let mut entries = entries
+            .into_iter()
+            .map(|(frame, (self_samples, total_samples))| ProfileEntry {
+                frame: frame.clone(),
+                self_samples,
+                total_samples,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            (b.self_samples, b.total_samples, &a.frame).cmp(&(
+                a.self_samples,
+                a.total_samples,
+                &b.frame,
+            ))
+        });
+        entries.truncate(n);
+        entries
+    }
+
+    /// A text table of `top(n)`.
+    pub fn summary(&self, n: usize) -> String {
+        let samples = self.samples().max(1) as f64;
+        let mut summary = format!(
+            "{:>7} {:>7} {:>8} {:>8}  function\n",
+            "self%", "total%", "self", "total"
+        );
+        for entry in self.top(n) {
+            summary.push_str(&format!(
+                "{:>6.1}% {:>6.1}% {:>8} {:>8}  {}\n",
+                entry.self_samples as f64 * 100.0 / samples,
+                entry.total_samples as f64 * 100.0 / samples,
+                entry.self_samples,
+                entry.total_samples,
+                entry.frame
+            ));
+        }
+        summary
+    }
+}
+
+/// A running profiler, stopped by `Actor::stop_profiler`.
+#[derive(Clone, Default)]
+pub struct Profiler {
+    profile: Arc<Mutex<Profile>>,
+}
+
+impl Profiler {
+    /// The profile sampled so far.
+    pub fn snapshot(&self) -> Profile {
+        self.profile.lock().unwrap().clone()
+    }
+
+    pub(crate) fn hook(&self) -> impl FnMut(Context, HookEvent) -> Result<(), Error> + Send {
+        let profile = self.profile.clone();
+        move |lua, event| {
+            if event != HookEvent::Count {
+                return Ok(());
+            }
+            let mut stack = vec![];
+            let mut level = 1;
+            while let Some(info) = frame_info(lua, level, "nS")? {
+                stack.push(frame(&info)?);
+                level += 1;
+            }
+            stack.reverse();
+            *profile.lock().unwrap().stacks.entry(stack).or_insert(0) += 1;
+            Ok(())
+        }
+    }
+}
+
+fn frame(info: &Table) -> Result<ProfileFrame, Error> {
+    let what = info.get::<_, String>("what")?;
+    let function = match info.get::<_, Option<String>>("name")? {
+        Some(name) => name,
+        None if what == "main" => "main chunk".to_string(),
+        None => "?".to_string(),
+    };
+    let source = info.get::<_, String>("source")?;
+    let file = match source.strip_prefix('@') {
+        Some(file) => file.to_string(),
+        None => info.get::<_, String>("short_src")?,
+    };
+    let line = info.get::<_, i64>("linedefined")?.max(0) as u32;
+    Ok(ProfileFrame {
+        function,
+        file,
+        line,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actor::Actor;
+
+    fn frame(function: &str, line: u32) -> ProfileFrame {
+        ProfileFrame {
+            function: function.to_string(),
+            file: "a.lua".to_string(),
+            line,
+        }
+    }
+
+    #[test]
+    fn reports() {
+        let (main, outer, inner) = (frame("main chunk", 0), frame("outer", 1), frame("inner", 5));
+        let mut profile = Profile::default();
+        profile
+            .stacks
+            .insert(vec![main.clone(), outer.clone(), inner.clone()], 3);
+        profile.stacks.insert(vec![main.clone(), outer.clone()], 1);
+
+        assert_eq!(4, profile.samples());
+        assert_eq!(
+            "main chunk (a.lua:0);outer (a.lua:1) 1\n\
+             main chunk (a.lua:0);outer (a.lua:1);inner (a.lua:5) 3\n",
+            profile.folded()
+        );
+        let top = profile.top(2);
+        assert_eq!(
+            vec![
+                ProfileEntry {
+                    frame: inner,
+                    self_samples: 3,
+                    total_samples: 3,
+                },
+                ProfileEntry {
+                    frame: outer,
+                    self_samples: 1,
+                    total_samples: 4,
+                },
+            ],
+            top
+        );
+        assert!(profile
+            .summary(1)
+            .contains("  75.0%   75.0%        3        3  inner (a.lua:5)"));
+    }
+
+    #[test]
+    fn sampling() {
+        fn test_actor(act: Actor) {
+            let profiler = act.start_profiler(100).unwrap();
+            act.exec_named(
+                r#"
+                local function busy(n)
+                    local sum = 0
+                    for i = 1, n do sum = sum + i % 7 end
+                    return sum
+                end
+                function run()
+                    local sum = busy(200000)
+                    return sum
+                end
+                run()
+            "#,
+                "busy.lua",
+            )
+            .unwrap();
+            let stopped = profiler.clone();
+            let profile = act.stop_profiler(profiler).unwrap();
+
+            let top = &profile.top(1)[0];
+            assert_eq!(
+                ("busy", "busy.lua", 2),
+                (
+                    top.frame.function.as_str(),
+                    top.frame.file.as_str(),
+                    top.frame.line
+                )
+            );
+            assert!(profile
+                .folded()
+                .contains("main chunk (busy.lua:0);run (busy.lua:7);busy (busy.lua:2) "));
+
+            // Nothing is sampled once stopped.
+            act.exec("run()").unwrap();
+            assert_eq!(profile.samples(), stopped.snapshot().samples());
+        }
+
+        test_actor(Actor::new_with_handler(None));
+        test_actor(Actor::new());
+    }
+}
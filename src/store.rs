@@ -0,0 +1,338 @@
+/*!
+A concurrent key-value store shared by many `Actor`s (and Rust code).
+
+Values are `LuaMessage`s, so they are copied in & out of each Lua VM.
+Function handles are bound to the VM which created them; don't share them here.
+*/
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actor::Actor;
+use message::LuaMessage;
+use rlua::{Context, Error, Table};
+
+struct Entry {
+    value: LuaMessage,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_alive(&self, now: Instant) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at > now,
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SharedStore {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl SharedStore {
+    pub fn new() -> SharedStore {
+        Default::default()
+    }
+
+    /// Get the value of the key, or `Nil` if it's missing or expired.
+    pub fn get(&self, key: &str) -> LuaMessage {
+        let now = Instant::now();
+        match self.entries.lock().unwrap().get(key) {
+            Some(entry) if entry.is_alive(now) => entry.value.clone(),
+            _ => LuaMessage::Nil,
+        }
+    }
+    /// Set the value of the key; setting `Nil` deletes it.
+    pub fn set(&self, key: &str, value: LuaMessage, ttl: Option<Duration>) {
+        let mut entries = self.entries.lock().unwrap();
+        Self::put(&mut entries, key, value, ttl);
+    }
+    /// Delete the key, returning whether it was there.
+    pub fn delete(&self, key: &str) -> bool {
+        let now = Instant::now();
+        match self.entries.lock().unwrap().remove(key) {
+            Some(entry) => entry.is_alive(now),
+            None => false,
+        }
+    }
+    /// Add `by` to the number of the key (missing keys count as 0), returning the new value.
+    ///
+    /// The ttl is only applied when it's given.
+    pub fn incr(
+        &self,
+        key: &str,
+        by: LuaMessage,
+        ttl: Option<Duration>,
+    ) -> Result<LuaMessage, Error> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let (current, expires_at) = match entries.get(key) {
+            Some(entry) if entry.is_alive(now) => (entry.value.clone(), entry.expires_at),
+            _ => (LuaMessage::Integer(0), None),
+        };
+        let value = match (current, by) {
+            (LuaMessage::Integer(a), LuaMessage::Integer(b)) => {
+                LuaMessage::Integer(a.wrapping_add(b))
+            }
+            (LuaMessage::Integer(a), LuaMessage::Number(b)) => LuaMessage::Number(a as f64 + b),
+            (LuaMessage::Number(a), LuaMessage::Integer(b)) => LuaMessage::Number(a + b as f64),
+            (LuaMessage::Number(a), LuaMessage::Number(b)) => LuaMessage::Number(a + b),
+            (current, by) => {
+                return Err(Error::RuntimeError(format!(
+                    "cannot increment {:?} of key '{}' by {:?}",
+                    current, key, by
+                )))
+            }
+        };
+        let expires_at = Self::expiry(now, ttl).or(expires_at);
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.clone(),
+                expires_at,
+            },
+        );
+        Ok(value)
+    }
+    /// Set the value only if the current one equals `expected` (`Nil` for missing keys).
+    pub fn cas(
+        &self,
+        key: &str,
+        expected: LuaMessage,
+        value: LuaMessage,
+        ttl: Option<Duration>,
+    ) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let current = match entries.get(key) {
+            Some(entry) if entry.is_alive(now) => entry.value.clone(),
+            _ => LuaMessage::Nil,
+        };
+        if current != expected {
+            return false;
+        }
+        Self::put(&mut entries, key, value, ttl);
+        true
+    }
+    /// Drop the expired entries (they are invisible anyway).
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|_, e| e.is_alive(now));
+    }
+
+    fn put(
+        entries: &mut HashMap<String, Entry>,
+        key: &str,
+        value: LuaMessage,
+        ttl: Option<Duration>,
+    ) {
+        if value == LuaMessage::Nil {
+            entries.remove(key);
+            return;
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                expires_at: Self::expiry(Instant::now(), ttl),
+            },
+        );
+    }
+
+    // TTLs beyond what `Instant` holds never expire.
+    fn expiry(now: Instant, ttl: Option<Duration>) -> Option<Instant> {
+        ttl.and_then(|ttl| now.checked_add(ttl))
+    }
+
+    /// Mount the store as the Lua module `name` of the actor (a global, also for `require`).
+    pub fn mount(&self, actor: &Actor, name: &str) -> Result<(), Error> {
+        actor
+            .lua()
+            .lock()
+            .unwrap()
+            .context(|lua| self.def_lua_module(lua, name))
+    }
+    /// Define `get(key)`, `set(key, value, ttl)`, `delete(key)`, `incr(key, by, ttl)`
+    /// & `cas(key, expected, value, ttl)` in the Lua module `name` (ttl in seconds).
+    pub fn def_lua_module(&self, lua: Context, name: &str) -> Result<(), Error> {
+        // Negative TTLs are expired; NaN & the ones too big for `Instant` are errors.
+        fn ttl(seconds: Option<f64>) -> Result<Option<Duration>, Error> {
+            let seconds = match seconds {
+                Some(seconds) => seconds,
+                None => return Ok(None),
+            };
+            match Duration::try_from_secs_f64(seconds.max(0_f64)) {
+                Ok(ttl) if !seconds.is_nan() && Instant::now().checked_add(ttl).is_some() => {
+                    Ok(Some(ttl))
+                }
+                _ => Err(Error::RuntimeError(format!("invalid ttl: {}", seconds))),
+            }
+        }
+
+        let module = lua.create_table()?;
+
+        let store = self.clone();
+        module.set(
+            "get",
+            lua.create_function(move |_, key: String| Ok(store.get(&key)))?,
+        )?;
+        let store = self.clone();
+        module.set(
+            "set",
+            lua.create_function(
+                move |_, (key, value, seconds): (String, LuaMessage, Option<f64>)| {
+                    store.set(&key, value, ttl(seconds)?);
+                    Ok(())
+                },
+            )?,
+        )?;
+        let store = self.clone();
+        module.set(
+            "delete",
+            lua.create_function(move |_, key: String| Ok(store.delete(&key)))?,
+        )?;
+        let store = self.clone();
+        module.set(
+            "incr",
+            lua.create_function(
+                move |_, (key, by, seconds): (String, Option<LuaMessage>, Option<f64>)| {
+                    store.incr(&key, by.unwrap_or(LuaMessage::Integer(1)), ttl(seconds)?)
+                },
+            )?,
+        )?;
+        let store = self.clone();
+        module.set(
+            "cas",
+            lua.create_function(
+                move |_, args: (String, LuaMessage, LuaMessage, Option<f64>)| {
+                    let (key, expected, value, seconds) = args;
+                    Ok(store.cas(&key, expected, value, ttl(seconds)?))
+                },
+            )?,
+        )?;
+
+        let globals = lua.globals();
+        if let Ok(package) = globals.get::<_, Table>("package") {
+            package
+                .get::<_, Table>("loaded")?
+                .set(name, module.clone())?;
+        }
+        globals.set(name, module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn rust_api() {
+        let store = SharedStore::new();
+        assert_eq!(LuaMessage::Nil, store.get("a"));
+        store.set("a", LuaMessage::from(1), None);
+        assert_eq!(LuaMessage::from(1), store.get("a"));
+
+        assert_eq!(
+            LuaMessage::from(3),
+            store.incr("a", LuaMessage::from(2), None).unwrap()
+        );
+        assert_eq!(
+            LuaMessage::from(3.5),
+            store.incr("a", LuaMessage::from(0.5), None).unwrap()
+        );
+        assert_eq!(
+            LuaMessage::from(1),
+            store.incr("b", LuaMessage::from(1), None).unwrap()
+        );
+        store.set("s", LuaMessage::from("x"), None);
+        assert!(store.incr("s", LuaMessage::from(1), None).is_err());
+
+        assert!(!store.cas("c", LuaMessage::from(1), LuaMessage::from(2), None));
+        assert!(store.cas("c", LuaMessage::Nil, LuaMessage::from(2), None));
+        assert!(store.cas("c", LuaMessage::from(2), LuaMessage::from(3), None));
+        assert_eq!(LuaMessage::from(3), store.get("c"));
+
+        assert!(store.delete("c"));
+        assert!(!store.delete("c"));
+
+        store.set("t", LuaMessage::from(true), Some(Duration::from_millis(20)));
+        assert_eq!(LuaMessage::from(true), store.get("t"));
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(LuaMessage::Nil, store.get("t"));
+        assert!(store.cas("t", LuaMessage::Nil, LuaMessage::from(1), None));
+
+        // Never expires.
+        store.set("forever", LuaMessage::from(1), Some(Duration::MAX));
+        assert_eq!(LuaMessage::from(1), store.get("forever"));
+    }
+
+    #[test]
+    fn invalid_ttls() {
+        let store = SharedStore::new();
+        let act = Actor::new_with_handler(None);
+        store.mount(&act, "shared").unwrap();
+        for ttl in &["math.huge", "0/0", "1e300"] {
+            for call in &[
+                format!("shared.set('a', 1, {})", ttl),
+                format!("shared.incr('a', 1, {})", ttl),
+                format!("shared.cas('a', nil, 1, {})", ttl),
+            ] {
+                let err = act.exec(call).err().unwrap();
+                assert!(err.to_string().contains("invalid ttl"), "{}: {}", call, err);
+            }
+        }
+        assert_eq!(LuaMessage::Nil, store.get("a"));
+        act.exec("shared.set('a', 1, -1)").unwrap();
+        assert_eq!(LuaMessage::Nil, store.get("a"));
+    }
+
+    #[test]
+    fn shared_between_actors() {
+        let store = SharedStore::new();
+        let act1 = Actor::new();
+        let act2 = Actor::new_with_handler(None);
+        store.mount(&act1, "shared").unwrap();
+        store.mount(&act2, "cache").unwrap();
+
+        act1.exec(
+            r#"
+            shared.set("config", { name = "demo" })
+            for i = 1, 10 do shared.incr("hits") end
+            shared.set("token", "abc", 0.02)
+        "#,
+        )
+        .unwrap();
+        act2.exec(
+            r#"
+            local cache = require("cache")
+            name = cache.get("config").name
+            hits = cache.incr("hits", 5)
+            swapped = cache.cas("hits", 15, 0)
+            stale = cache.cas("hits", 15, 1)
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("demo".to_string()),
+            Option::from(act2.get_global("name").unwrap())
+        );
+        assert_eq!(Some(15), Option::from(act2.get_global("hits").unwrap()));
+        assert_eq!(
+            Some(true),
+            Option::from(act2.get_global("swapped").unwrap())
+        );
+        assert_eq!(Some(false), Option::from(act2.get_global("stale").unwrap()));
+        assert_eq!(LuaMessage::from(0), store.get("hits"));
+
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(
+            LuaMessage::Nil,
+            act1.eval(r#"shared.get("token")"#).unwrap()
+        );
+    }
+}
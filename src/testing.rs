@@ -0,0 +1,519 @@
+/*!
+A runner of Lua unit tests (`*_test.lua` files), each file in a fresh `Actor`.
+
+Test files use `describe(name, body)`, `it(name, body)`, `before_each(f)`, `after_each(f)`
+& `expect(value).to_be(x)` (also `to_equal`, `to_be_truthy`, `to_be_falsy`, `to_be_nil`
+& `to_throw(text)` for functions), e.g.:
+
+```lua
+describe("math", function()
+    it("adds", function()
+        expect(1 + 1).to_be(2)
+        expect({ 1, { a = 2 } }).to_equal({ 1, { a = 2 } })
+    end)
+end)
+```
+
+From a Rust test: `lua_actor::testing::assert_lua_tests("tests/lua")`.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use actor::Actor;
+use error::ScriptError;
+use message::{LuaFunctionRef, LuaMessage};
+use rlua::Error;
+
+const TEST_FILE_SUFFIX: &str = "_test.lua";
+const PRELUDE_CHUNK_NAME: &str = "lua_actor/testing.lua";
+const PRELUDE: &str = r#"
+local tests, scopes = {}, {}
+
+local function scope(name)
+    local current = scopes[#scopes]
+    if not current then
+        error(name .. " must be called in describe()", 3)
+    end
+    return current
+end
+
+function describe(name, body)
+    table.insert(scopes, { name = name, before = {}, after = {} })
+    body()
+    table.remove(scopes)
+end
+function before_each(f)
+    table.insert(scope("before_each").before, f)
+end
+function after_each(f)
+    table.insert(scope("after_each").after, f)
+end
+function it(name, body)
+    local names, before, after = {}, {}, {}
+    for _, s in ipairs(scopes) do
+        table.insert(names, s.name)
+        for _, f in ipairs(s.before) do table.insert(before, f) end
+        for _, f in ipairs(s.after) do table.insert(after, 1, f) end
+    end
+    table.insert(names, name)
+    table.insert(tests, { name = table.concat(names, " "), body = body, before = before, after = after })
+end
+
+local function show(value)
+    if type(value) == "string" then
+        return string.format("%q", value)
+    elseif type(value) ~= "table" then
+        return tostring(value)
+    end
+    local keys = {}
+    for k in pairs(value) do table.insert(keys, k) end
+    table.sort(keys, function(a, b) return tostring(a) < tostring(b) end)
+    local fields = {}
+    for _, k in ipairs(keys) do
+        local key = type(k) == "string" and k or "[" .. tostring(k) .. "]"
+        table.insert(fields, key .. " = " .. show(value[k]))
+    end
+    return "{" .. table.concat(fields, ", ") .. "}"
+end
+
+local function equal(a, b)
+    if type(a) ~= "table" or type(b) ~= "table" then
+        return a == b
+    end
+    for k, v in pairs(a) do
+        if not equal(v, b[k]) then return false end
+    end
+    for k in pairs(b) do
+        if a[k] == nil then return false end
+    end
+    return true
+end
+
+function expect(actual)
+    local function check(ok, message, expected)
+        if not ok then
+            error(string.format(message, show(actual), show(expected)), 3)
+        end
+    end
+    return {
+        to_be = function(expected)
+            check(actual == expected, "expected %s to be %s", expected)
+        end,
+        to_equal = function(expected)
+            check(equal(actual, expected), "expected %s to equal %s", expected)
+        end,
+        to_be_truthy = function()
+            check(actual, "expected %s to be truthy")
+        end,
+        to_be_falsy = function()
+            check(not actual, "expected %s to be falsy")
+        end,
+        to_be_nil = function()
+            check(actual == nil, "expected %s to be nil")
+        end,
+        to_throw = function(text)
+            local ok, err = pcall(actual)
+            check(not ok, "expected %s to throw")
+            if text then
+                check(string.find(tostring(err), text, 1, true), "expected %s to throw %s", text)
+            end
+        end,
+    }
+end
+
+local function run(i)
+    local test = tests[i]
+    local ok, err = true, nil
+    for _, f in ipairs(test.before) do
+        if ok then ok, err = pcall(f) end
+    end
+    if ok then ok, err = pcall(test.body) end
+    for _, f in ipairs(test.after) do
+        local after_ok, after_err = pcall(f)
+        if ok and not after_ok then ok, err = after_ok, after_err end
+    end
+    return { name = test.name, error = (not ok) and tostring(err) or nil }
+end
+
+return { count = function() return #tests end, run = run }
+"#;
+
+/// The result of a test case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    /// The names of its `describe` scopes & its own, joined by spaces.
+    pub name: String,
+    pub duration: Duration,
+    /// The error message of a failed test.
+    pub failure: Option<String>,
+}
+
+/// The results of a test file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestFile {
+    pub path: PathBuf,
+    pub cases: Vec<TestCase>,
+    pub duration: Duration,
+}
+
+impl TestFile {
+    pub fn failures(&self) -> usize {
+        self.cases.iter().filter(|c| c.failure.is_some()).count()
+    }
+}
+
+/// The results of test files, reported in human readable text or JUnit XML.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TestReport {
+    pub files: Vec<TestFile>,
+}
+
+impl TestReport {
+    pub fn tests(&self) -> usize {
+        self.files.iter().map(|f| f.cases.len()).sum()
+    }
+    pub fn failures(&self) -> usize {
+        self.files.iter().map(TestFile::failures).sum()
+    }
+    pub fn is_success(&self) -> bool {
+        self.failures() == 0
+    }
+
+    pub fn human(&self) -> String {
+        let mut text = String::new();
+        for file in &self.files {
+            text.push_str(&format!("{}\n", file.path.display()));
+            for case in &file.cases {
+                let millis = case.duration.as_secs_f64() * 1000.0;
+                match case.failure {
+                    None => text.push_str(&format!("  PASS {} ({:.1} ms)\n", case.name, millis)),
+                    Some(ref failure) => {
+                        text.push_str(&format!("  FAIL {} ({:.1} ms)\n", case.name, millis));
+                        for line in failure.lines() {
+                            text.push_str(&format!("       {}\n", line));
+                        }
+                    }
+                }
+            }
+        }
+        text.push_str(&format!(
+            "{} passed, {} failed\n",
+            self.tests() - self.failures(),
+            self.failures()
+        ));
+        text
+    }
+
+    pub fn junit(&self) -> String {
+        let seconds = |d: Duration| format!("{:.3}", d.as_secs_f64());
+        let total = self.files.iter().map(|f| f.duration).sum::<Duration>();
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuites tests=\"{}\" failures=\"{}\" time=\"{}\">\n",
+            self.tests(),
+            self.failures(),
+            seconds(total)
+        );
+        for file in &self.files {
+            let suite = xml_escape(&file.path.display().to_string());
+            xml.push_str(&format!(
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{}\">\n",
+                suite,
+                file.cases.len(),
+                file.failures(),
+                seconds(file.duration)
+            ));
+            for case in &file.cases {
+                xml.push_str(&format!(
+                    "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\"",
+                    xml_escape(&case.name),
+                    suite,
+                    seconds(case.duration)
+                ));
+                match case.failure {
+                    None => xml.push_str("/>\n"),
+                    Some(ref failure) => {
+                        let first_line = failure.lines().next().unwrap_or_default();
+                        xml.push_str(&format!(
+                            ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                            xml_escape(first_line),
+                            xml_escape(failure)
+                        ));
+                    }
+                }
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Kept as is in the attributes too.
+            '\t' | '\n' | '\r' => escaped.push_str(&format!("&#{};", c as u32)),
+            // The other control characters aren't XML 1.0, even as references.
+            c if c.is_control() || c == '\u{fffe}' || c == '\u{ffff}' => {
+                escaped.extend(c.escape_unicode())
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Find the `*_test.lua` files under the directory (sorted).
+pub fn discover(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(discover(&path)?);
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(TEST_FILE_SUFFIX))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Run the test file in a fresh `Actor`.
+///
+/// Errors raised while loading the file are reported as a failed "(load)" case.
+pub fn run_file(path: impl AsRef<Path>) -> TestFile {
+    let path = path.as_ref();
+    let started = Instant::now();
+    let cases = match run_cases(path) {
+        Ok(cases) => cases,
+        Err(err) => vec![TestCase {
+            name: "(load)".to_string(),
+            duration: started.elapsed(),
+            failure: Some(failure_message(&err)),
+        }],
+    };
+    TestFile {
+        path: path.to_path_buf(),
+        cases,
+        duration: started.elapsed(),
+    }
+}
+
+fn run_cases(path: &Path) -> Result<Vec<TestCase>, Error> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| Error::RuntimeError(format!("cannot read {}: {}", path.display(), err)))?;
+    let actor = Actor::new_with_handler(None);
+    let (count, run) = match actor.exec_named(PRELUDE, PRELUDE_CHUNK_NAME)? {
+        LuaMessage::Table(mut runner) => (
+            Option::<LuaFunctionRef>::from(runner.remove("count").unwrap_or(LuaMessage::Nil)),
+            Option::<LuaFunctionRef>::from(runner.remove("run").unwrap_or(LuaMessage::Nil)),
+        ),
+        _ => (None, None),
+    };
+    let (count, run) = count.zip(run).ok_or_else(|| {
+        Error::RuntimeError("the test runner has no count & run functions".to_string())
+    })?;
+    actor.exec_named(&source, &path.display().to_string())?;
+
+    let count = Option::<i64>::from(actor.call_function(&count, ())?).unwrap_or(0);
+    (1..=count)
+        .map(|i| {
+            let started = Instant::now();
+            let result = actor.call_function(&run, i)?;
+            let duration = started.elapsed();
+            let mut result = Option::<std::collections::HashMap<String, LuaMessage>>::from(result)
+                .unwrap_or_default();
+            Ok(TestCase {
+                name: Option::from(result.remove("name").unwrap_or(LuaMessage::Nil))
+                    .unwrap_or_default(),
+                duration,
+                failure: Option::from(result.remove("error").unwrap_or(LuaMessage::Nil)),
+            })
+        })
+        .collect()
+}
+
+fn failure_message(err: &Error) -> String {
+    match ScriptError::find(err) {
+        Some(err) => format!("{}:{}: {}", err.file, err.line, err.message),
+        None => err.to_string(),
+    }
+}
+
+/// Run the `*_test.lua` files under the directory.
+pub fn run_dir(dir: impl AsRef<Path>) -> io::Result<TestReport> {
+    Ok(TestReport {
+        files: discover(dir)?.into_iter().map(run_file).collect(),
+    })
+}
+
+/// Run the `*_test.lua` files under the directory, panicking with the report on failures.
+pub fn assert_lua_tests(dir: impl AsRef<Path>) {
+    let dir = dir.as_ref();
+    let report = run_dir(dir)
+        .unwrap_or_else(|err| panic!("cannot find the Lua tests in {}: {}", dir.display(), err));
+    assert!(
+        report.tests() > 0,
+        "no Lua tests (*{}) found in {}",
+        TEST_FILE_SUFFIX,
+        dir.display()
+    );
+    assert!(report.is_success(), "Lua tests failed:\n{}", report.human());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("lua_actor_testing_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, source) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn passing_tests() {
+        let dir = fixture(
+            "passing",
+            &[
+                (
+                    "math_test.lua",
+                    r#"
+                    describe("math", function()
+                        local n
+                        before_each(function() n = 1 end)
+                        it("adds", function()
+                            expect(n + 1).to_be(2)
+                            n = 10
+                        end)
+                        it("resets before each", function()
+                            expect(n).to_be(1)
+                        end)
+                        describe("tables", function()
+                            it("compares deeply", function()
+                                expect({ 1, { a = "x" } }).to_equal({ 1, { a = "x" } })
+                                expect(nil).to_be_nil()
+                                expect(0).to_be_truthy()
+                                expect(false).to_be_falsy()
+                                expect(function() error("boom") end).to_throw("boom")
+                            end)
+                        end)
+                    end)
+                "#,
+                ),
+                ("nested/more_test.lua", r#"it("runs", function() end)"#),
+                ("helper.lua", "error('not a test')"),
+            ],
+        );
+
+        assert_eq!(2, discover(&dir).unwrap().len());
+        let report = run_dir(&dir).unwrap();
+        assert_eq!((4, 0), (report.tests(), report.failures()));
+        assert_eq!(
+            vec![
+                "math adds",
+                "math resets before each",
+                "math tables compares deeply"
+            ],
+            report.files[0]
+                .cases
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_lua_tests(&dir);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failing_tests() {
+        let dir = fixture(
+            "failing",
+            &[
+                (
+                    "fail_test.lua",
+                    "describe(\"checks\", function()\n\
+                     \x20   it(\"fails\", function()\n\
+                     \x20       expect({ 1 }).to_equal({ 2 })\n\
+                     \x20   end)\n\
+                     \x20   it(\"passes\", function() end)\n\
+                     end)\n",
+                ),
+                ("broken_test.lua", "describe(\"broken\", function()\n"),
+            ],
+        );
+
+        let report = run_dir(&dir).unwrap();
+        assert_eq!((3, 2), (report.tests(), report.failures()));
+        let broken = &report.files[0].cases[0];
+        assert_eq!("(load)", broken.name);
+        let failure = report.files[1].cases[0].failure.clone().unwrap();
+        assert!(failure.ends_with("fail_test.lua:3: expected {[1] = 1} to equal {[1] = 2}"));
+
+        let human = report.human();
+        assert!(human.contains("  FAIL checks fails"));
+        assert!(human.contains("  PASS checks passes"));
+        assert!(human.ends_with("1 passed, 2 failed\n"));
+        let junit = report.junit();
+        assert!(junit.contains("<testsuites tests=\"3\" failures=\"2\""));
+        assert!(junit.contains("<testcase name=\"checks passes\""));
+        assert!(junit.contains("<failure message=\""));
+
+        let result = std::panic::catch_unwind(|| assert_lua_tests(&dir));
+        assert!(result.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn junit_escaping() {
+        let report = TestReport {
+            files: vec![TestFile {
+                path: PathBuf::from("a&b_test.lua"),
+                cases: vec![TestCase {
+                    name: "it's <\"quoted\">\u{1b}[31m".to_string(),
+                    duration: Duration::default(),
+                    failure: Some("bad\u{0}\tvalue\nat line 2".to_string()),
+                }],
+                duration: Duration::default(),
+            }],
+        };
+        let junit = report.junit();
+        assert!(
+            junit.contains("<testsuite name=\"a&amp;b_test.lua\""),
+            "{}",
+            junit
+        );
+        assert!(
+            junit.contains("name=\"it&apos;s &lt;&quot;quoted&quot;&gt;\\u{1b}[31m\""),
+            "{}",
+            junit
+        );
+        assert!(
+            junit.contains("message=\"bad\\u{0}&#9;value\">bad\\u{0}&#9;value&#10;at line 2<"),
+            "{}",
+            junit
+        );
+        assert!(!junit.chars().any(|c| c.is_control() && c != '\n'));
+    }
+}
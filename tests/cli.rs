@@ -0,0 +1,114 @@
+#![cfg(feature = "cli")]
+
+extern crate serde_json;
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use serde_json::Value as Json;
+
+const SCRIPT: &str = "tests/fixtures/orders.lua";
+
+fn lua_actor(args: &[&str], stdin: Option<&str>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lua-actor"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    if let Some(input) = stdin {
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+    }
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> Json {
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn json_error(output: &Output) -> Json {
+    let error: Json = serde_json::from_slice(&output.stderr).unwrap();
+    error["error"].clone()
+}
+
+#[test]
+fn results() {
+    let output = lua_actor(&["-c", "add", "-a", "[1, 2]", SCRIPT], None);
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(Json::from(3), stdout(&output));
+
+    // Many values as an array.
+    let output = lua_actor(&["--call", "divmod", "--args", "[7, 2]", SCRIPT], None);
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(serde_json::json!([3, 1]), stdout(&output));
+}
+
+#[test]
+fn args() {
+    // A single value is the only argument.
+    let order = r#"{"id": "A1", "items": [1, 2, 3]}"#;
+    let output = lua_actor(&["-c", "describe", "-a", order, SCRIPT], None);
+    assert_eq!(Json::from("A1: 3 items"), stdout(&output));
+
+    let output = lua_actor(&["-c", "add", "-a", "-", SCRIPT], Some("[40, 2]"));
+    assert_eq!(Json::from(42), stdout(&output));
+
+    // No arguments.
+    let output = lua_actor(&["-c", "os.time", SCRIPT], None);
+    assert_eq!(Some(0), output.status.code());
+    assert!(stdout(&output).is_number());
+}
+
+#[test]
+fn usage_errors() {
+    for args in &[
+        vec!["-c", "add", "-a", "[1,", SCRIPT],
+        vec!["-a", "[]", SCRIPT],
+        vec!["-c", "add", "--unknown", SCRIPT],
+        vec!["-c"],
+    ] {
+        let output = lua_actor(args, None);
+        assert_eq!(Some(2), output.status.code(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("usage: lua-actor"), "{}", stderr);
+    }
+    let output = lua_actor(&["-c", "add", "-a", "[1,", SCRIPT], None);
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("invalid --args"));
+}
+
+#[test]
+fn lua_errors() {
+    let order = r#"{"id": "A1", "paid": false}"#;
+    let output = lua_actor(&["-c", "check", "-a", order, SCRIPT], None);
+    assert_eq!(Some(1), output.status.code());
+    assert!(output.stdout.is_empty());
+    let error = json_error(&output);
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("order A1 is not paid"));
+    assert_eq!(Json::from(SCRIPT), error["file"]);
+    assert_eq!(Json::from(15), error["line"]);
+    assert!(error["source_line"].as_str().unwrap().contains("error("));
+    assert!(error["traceback"].is_string());
+
+    // Errors outside of the scripts have only a message.
+    let output = lua_actor(&["-c", "missing", SCRIPT], None);
+    assert_eq!(Some(1), output.status.code());
+    let error = json_error(&output);
+    assert!(error["message"].is_string(), "{}", error);
+    assert_eq!(None, error.get("file"));
+
+    let output = lua_actor(&["-c", "add", "tests/fixtures/missing.lua"], None);
+    assert_eq!(Some(1), output.status.code());
+    assert!(json_error(&output)["message"]
+        .as_str()
+        .unwrap()
+        .contains("cannot read tests/fixtures/missing.lua"));
+}
@@ -0,0 +1,18 @@
+function add(a, b)
+    return a + b
+end
+
+function divmod(a, b)
+    return a // b, a % b
+end
+
+function describe(order)
+    return order.id .. ": " .. #order.items .. " items"
+end
+
+function check(order)
+    if not order.paid then
+        error("order " .. order.id .. " is not paid")
+    end
+    return true
+end